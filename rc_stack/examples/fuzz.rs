@@ -39,7 +39,7 @@ fn run(max_iterations: usize, log: &Mutex<Vec<String>>) {
             });
             continue;
         }
-        match rng.gen_range(0..9) {
+        match rng.gen_range(0..11) {
             0 => {
                 let var = &mut vars[idx];
                 let elem = rng.gen_range(0..100);
@@ -69,7 +69,7 @@ fn run(max_iterations: usize, log: &Mutex<Vec<String>>) {
                 lg(format!("{}.peek();", var.name));
                 let e1 = var.v.last().cloned();
                 let e2 = var.r.peek().cloned();
-                let e3 = var.s.peek().map(|e| e.clone());
+                let e3 = var.s.peek().map(|e| *e);
                 assert_eq!(e1, e2);
                 assert_eq!(e1, e3);
             }
@@ -115,6 +115,24 @@ fn run(max_iterations: usize, log: &Mutex<Vec<String>>) {
                 lg(format!("{}.check();", var.name));
                 var.s.check();
             }
+            9 => {
+                let var = &vars[idx];
+                lg(format!("{}.to_vec();", var.name));
+                let e1: Vec<i32> = var.v.iter().rev().cloned().collect();
+                let e2 = var.r.to_vec();
+                let e3 = var.s.to_vec();
+                assert_eq!(e1, e2);
+                assert_eq!(e1, e3);
+            }
+            10 => {
+                let var = &vars[idx];
+                lg(format!("{}.len();", var.name));
+                let e1 = var.v.len();
+                let e2 = var.r.len();
+                let e3 = var.s.len();
+                assert_eq!(e1, e2);
+                assert_eq!(e1, e3);
+            }
             _ => panic!()
         }
     }
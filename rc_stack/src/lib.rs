@@ -2,4 +2,4 @@ mod rc_stack_simple;
 mod rc_stack;
 
 pub use crate::rc_stack_simple::RcStack as RcStackSimple;
-pub use crate::rc_stack::RcStack;
\ No newline at end of file
+pub use crate::rc_stack::{RcStack, live_items, enable_counting};
\ No newline at end of file
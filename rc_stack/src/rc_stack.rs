@@ -1,7 +1,33 @@
 use std::rc::Rc;
-use std::cell::{RefCell, Ref};
+use std::cell::{Cell, RefCell, Ref};
 use std::mem;
 
+thread_local! {
+    // None until counting is enabled
+    static LIVE_ITEMS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Starts counting elements stored in all stacks of this thread,
+/// see `live_items()`. Elements pushed before are not counted.
+pub fn enable_counting() {
+    LIVE_ITEMS.with(|n| if n.get().is_none() { n.set(Some(0)) });
+}
+
+/// Total number of elements stored in all stacks of this thread
+/// (shared elements are counted once), 0 if counting is not enabled.
+pub fn live_items() -> usize {
+    LIVE_ITEMS.with(|n| n.get().unwrap_or(0))
+}
+
+fn items_added(k: usize) {
+    LIVE_ITEMS.with(|n| if let Some(c) = n.get() { n.set(Some(c + k)) });
+}
+
+fn items_removed(k: usize) {
+    // the elements might have been pushed before counting was enabled
+    LIVE_ITEMS.with(|n| if let Some(c) = n.get() { n.set(Some(c.saturating_sub(k))) });
+}
+
 #[derive(Default, Debug)]
 pub struct RcStack<T>(Link<T>);
 
@@ -11,6 +37,8 @@ type Link<T> = Option<(Rc<Block<T>>, usize)>;
 struct Block<T> {
     items: RefCell<Vec<(T, usize)>>,  // never empty
     tail: RcStack<T>,
+    // tail.len(), so that len() doesn't have to walk the chain
+    base: usize,
 }
 
 impl<T> Drop for RcStack<T> {
@@ -22,6 +50,7 @@ impl<T> Drop for RcStack<T> {
                 items[idx].1 -= 1;
                 while !items.is_empty() && items[items.len() - 1].1 == 0 {
                     items.pop();
+                    items_removed(1);
                     // TODO: downsize when too much capacity is wasted
                 }
             }
@@ -56,13 +85,14 @@ impl<T> RcStack<T> {
             assert!(!items.is_empty());
             assert!(items.last().unwrap().1 > 0);
             assert_eq!(Rc::strong_count(b), items.iter().map(|i| i.1).sum());
+            assert_eq!(b.base, b.tail.len());
 
             p = &b.tail;
         }
         (block_size, num_blocks)
     }
 
-    pub fn peek(&self) -> Option<Ref<T>> {
+    pub fn peek(&self) -> Option<Ref<'_, T>> {
         match self.0 {
             Some((ref block, idx)) => {
                 let items = block.items.borrow();
@@ -73,6 +103,7 @@ impl<T> RcStack<T> {
     }
 
     pub fn push(&mut self, elem: T) {
+        items_added(1);
         if let Some((ref mut block, ref mut idx)) = self.0 {
             let mut items = block.items.borrow_mut();
             if *idx + 1 == items.len() {
@@ -86,6 +117,7 @@ impl<T> RcStack<T> {
         let tail = mem::replace(self, RcStack::new());
         let block = Block {
             items: RefCell::new(vec![(elem, 1)]),
+            base: tail.len(),
             tail,
         };
         self.0 = Some((Rc::new(block), 0));
@@ -95,6 +127,14 @@ impl<T> RcStack<T> {
         self.0.is_none()
     }
 
+    /// Number of elements, takes constant time.
+    pub fn len(&self) -> usize {
+        match self.0 {
+            Some((ref block, idx)) => block.base + idx + 1,
+            None => 0,
+        }
+    }
+
     /// Attempts to pop an element from a stack and apply a function
     /// to it. If the stack is empty, calls `empty_fn()`, if the
     /// top element is unique (not shared with other stacks) calls
@@ -124,6 +164,7 @@ impl<T> RcStack<T> {
                 let mut items = block.items.borrow_mut();
                 items[*idx].1 -= 1;
                 let result = if *idx + 1 == items.len() && items[*idx].1 == 0 {
+                    items_removed(1);
                     unique_fn(items.pop().unwrap().0)
                     // TODO: downsize when too much capacity is wasted
                 } else {
@@ -175,6 +216,18 @@ impl<T: Clone> RcStack<T> {
         }
         result
     }
+
+    /// Copies all elements, top of the stack first.
+    pub fn to_vec(&self) -> Vec<T> {
+        let mut result = Vec::new();
+        let mut p = self;
+        while let Some((ref b, idx)) = p.0 {
+            let items = b.items.borrow();
+            result.extend(items[..=idx].iter().rev().map(|i| i.0.clone()));
+            p = &b.tail;
+        }
+        result
+    }
 }
 
 impl<T> Clone for RcStack<T> {
@@ -215,12 +268,32 @@ mod tests {
         assert_eq!(a.pop_clone(), None);
         assert_eq!(a.pop_clone(), None);
 
+        assert_eq!(b.to_vec(), [20, 10]);
+        assert_eq!(b.len(), 2);
         assert_eq!(b.pop_clone(), Some(20));
         assert_eq!(b.pop_clone(), Some(10));
         assert_eq!(b.pop_clone(), None);
         assert_eq!(b.pop_clone(), None);
     }
 
+    #[test]
+    fn live_items_counting() {
+        enable_counting();
+        let before = live_items();
+        let mut a = RcStack::new();
+        a.push(1);
+        a.push(2);
+        let mut b = a.clone();
+        b.push(3);
+        assert_eq!(live_items(), before + 3);
+        a.discard_top();
+        assert_eq!(live_items(), before + 3);
+        drop(b);
+        assert_eq!(live_items(), before + 1);
+        drop(a);
+        assert_eq!(live_items(), before);
+    }
+
     #[test]
     fn drop_is_non_recursive() {
         let mut s = RcStack::new();
@@ -31,6 +31,16 @@ impl<T> RcStack<T> {
         self.0.is_none()
     }
 
+    pub fn len(&self) -> usize {
+        let mut n = 0;
+        let mut p = self;
+        while let Some(ref q) = p.0 {
+            n += 1;
+            p = &q.1;
+        }
+        n
+    }
+
     pub fn discard_top(&mut self) -> bool {
         match self.0 {
             Some(ref p) => {
@@ -71,6 +81,17 @@ impl<T: Clone> RcStack<T> {
             }
         }
     }
+
+    /// Copies all elements, top of the stack first.
+    pub fn to_vec(&self) -> Vec<T> {
+        let mut result = Vec::new();
+        let mut p = self;
+        while let Some(ref q) = p.0 {
+            result.push(q.0.clone());
+            p = &q.1;
+        }
+        result
+    }
 }
 
 impl<T> Clone for RcStack<T> {
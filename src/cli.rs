@@ -0,0 +1,257 @@
+// Subcommands and their options, help is generated from these tables.
+
+use unlambda::interpreter;
+
+pub struct OptionHelp {
+    pub flag: &'static str,
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+const fn opt(flag: &'static str, value: Option<&'static str>, help: &'static str) -> OptionHelp {
+    OptionHelp { flag, value, help }
+}
+
+/// Options shared by several commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Group {
+    Source,
+    Encoding,
+    Interpreter,
+    Limits,
+    Input,
+    Report,
+    Trace,
+    Expand,
+    Format,
+    Bench,
+}
+
+const SOURCE: &[OptionHelp] = &[
+    opt("-e", Some("PROGRAM"), "\
+Use the program given on the command line instead of files,
+can be repeated like files"),
+    opt("--lambda", None, "\
+The program is written in lambda calculus: \\x y. M, see unlambda::lambda"),
+    opt("--abstraction", Some("ALGORITHM"), "\
+Used to compile lambda calculus: naive or turner (default), implies --lambda"),
+];
+
+const ENCODING: &[OptionHelp] = &[
+    opt("--encoding", Some("ENCODING"), "\
+How characters are read and printed: utf8 (default),
+bytes (each byte is a character U+0000..U+00FF, also in the source)
+or ascii (anything else in the source is an error, in the input or
+output it stops with exit code 7, as other I/O errors do)"),
+];
+
+const INTERPRETER: &[OptionHelp] = &[
+    opt("--interpreter", Some("NAME"), "\
+Possible values: {interpreters} (default: {default})"),
+];
+
+const LIMITS: &[OptionHelp] = &[
+    opt("--max-steps", Some("N"), "\
+Stop after N applications with exit code 3"),
+    opt("--max-memory", Some("SIZE"), "\
+Stop with exit code 4 when terms and continuations take more
+than SIZE bytes (approximately), K, M and G suffixes are allowed;
+continuations are only counted by the smallstep interpreter,
+so the others can take more"),
+    opt("--timeout", Some("SECONDS"), "\
+Stop with exit code 5 when the time runs out"),
+];
+
+const INPUT: &[OptionHelp] = &[
+    opt("--input", Some("FILE"), "\
+Read the input of the program from FILE instead of stdin"),
+    opt("--input-string", Some("STR"), "\
+Use STR (as UTF-8 bytes, like from a shell pipe) as the input"),
+    opt("--eof", Some("BEHAVIOR"), "\
+What `@` reads after the end of the input:
+hard (default) is EOF, repeat starts the input over"),
+];
+
+const REPORT: &[OptionHelp] = &[
+    opt("--time", None, "\
+Print execution time to stderr"),
+    opt("--stats", None, "\
+Print application counts, peak continuation depth,
+peak live terms and allocations to stderr"),
+    opt("--print-result", None, "\
+Print the resulting term to stderr as Unlambda source (unless it
+contains a continuation), or the argument of `e` if the program exited with it"),
+    opt("--result-status", None, "\
+Exit with code 0 if the result (or the argument of `e`) is i,
+8 if it is v and 6 otherwise, to use programs as predicates
+(other failures have other codes, e.g. 1 for invalid arguments)"),
+];
+
+const TRACE: &[OptionHelp] = &[
+    opt("--trace", None, "\
+Log applications, promises, continuations and I/O to stderr"),
+    opt("--trace-file", Some("FILE"), "\
+Log them to a file instead"),
+    opt("--trace-width", Some("N"), "\
+Truncate terms in the trace to N characters
+(default: 60 for text, no truncation for JSON)"),
+    opt("--trace-filter", Some("COMBINATORS"), "\
+Comma-separated list, e.g. k,s,d,c,.x,@,?x,|,<cont>,promise"),
+    opt("--trace-format", Some("FORMAT"), "\
+text (default) or json (JSON lines, see unlambda::trace::JsonTracer)"),
+];
+
+const EXPAND: &[OptionHelp] = &[
+    opt("--expand", None, "\
+Print the program with `let` definitions expanded and exit
+(or compiled to combinators with --lambda), like the compile command"),
+];
+
+const FORMAT: &[OptionHelp] = &[
+    opt("--width", Some("N"), "\
+Break lines longer than N characters (default: 80)"),
+    opt("--strip-comments", None, "\
+Leave comments out"),
+];
+
+const BENCH: &[OptionHelp] = &[
+    opt("--runs", Some("N"), "\
+Number of runs for each interpreter (default: 5)"),
+];
+
+impl Group {
+    pub fn options(self) -> &'static [OptionHelp] {
+        match self {
+            Group::Source => SOURCE,
+            Group::Encoding => ENCODING,
+            Group::Interpreter => INTERPRETER,
+            Group::Limits => LIMITS,
+            Group::Input => INPUT,
+            Group::Report => REPORT,
+            Group::Trace => TRACE,
+            Group::Expand => EXPAND,
+            Group::Format => FORMAT,
+            Group::Bench => BENCH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Command {
+    #[default]
+    Run,
+    Check,
+    Fmt,
+    Trace,
+    Bench,
+    Compile,
+    Minify,
+    Repl,
+}
+
+impl Command {
+    pub const ALL: [Command; 8] = [
+        Command::Run,
+        Command::Check,
+        Command::Fmt,
+        Command::Trace,
+        Command::Bench,
+        Command::Compile,
+        Command::Minify,
+        Command::Repl,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::Run => "run",
+            Command::Check => "check",
+            Command::Fmt => "fmt",
+            Command::Trace => "trace",
+            Command::Bench => "bench",
+            Command::Compile => "compile",
+            Command::Minify => "minify",
+            Command::Repl => "repl",
+        }
+    }
+
+    pub fn find(name: &str) -> Option<Command> {
+        Command::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    fn summary(self) -> &'static str {
+        match self {
+            Command::Run => "Run a program (the default command)",
+            Command::Check => "Parse a program, report errors and the features it uses",
+            Command::Fmt => "Pretty-print a program without `let` or lambda calculus, indented to show its applications",
+            Command::Trace => "Run a program logging every step, like run --trace",
+            Command::Bench => "Time a program on each interpreter, discarding its output",
+            Command::Compile => "Print a program with `let` and lambda calculus compiled away",
+            Command::Minify => "Print a program in as few characters as possible, without a newline",
+            Command::Repl => "Evaluate expressions interactively",
+        }
+    }
+
+    pub fn groups(self) -> &'static [Group] {
+        use Group::*;
+        match self {
+            Command::Run => &[Source, Encoding, Interpreter, Limits, Input, Report, Trace, Expand],
+            Command::Check | Command::Compile | Command::Minify => &[Source, Encoding],
+            Command::Fmt => &[Source, Encoding, Format],
+            Command::Trace => &[Source, Encoding, Interpreter, Limits, Input, Trace],
+            Command::Bench => &[Source, Encoding, Interpreter, Limits, Input, Bench],
+            Command::Repl => &[Interpreter, Encoding],
+        }
+    }
+
+    pub fn has(self, group: Group) -> bool {
+        self.groups().contains(&group)
+    }
+
+    /// Whether it takes program files (or `-e`).
+    pub fn takes_program(self) -> bool {
+        self.has(Group::Source)
+    }
+}
+
+pub fn overview() -> String {
+    let mut s = String::from("\
+USAGE:
+    unlambda [COMMAND] [OPTIONS] (<FILE>... | -e <PROGRAM>...)
+
+COMMANDS:
+");
+    for c in Command::ALL {
+        s.push_str(&format!("    {:<10}{}\n", c.name(), c.summary()));
+    }
+    s.push_str("\nSee `unlambda <COMMAND> --help` for the options of each command.\n");
+    s
+}
+
+pub fn help(command: Command) -> String {
+    let mut s = format!("{}\n\nUSAGE:\n    unlambda {} [OPTIONS]", command.summary(), command.name());
+    if command.takes_program() {
+        s.push_str(" (<FILE>... | -e <PROGRAM>...)\n\n");
+        s.push_str("    <FILE>...\n");
+        s.push_str("        Program files, - reads the program from stdin. The programs\n");
+        s.push_str("        of several files are applied to each other: a b c means ``abc\n");
+    } else {
+        s.push('\n');
+    }
+    let interpreters: Vec<&str> = interpreter::all().iter().map(|i| i.name()).collect();
+    for group in command.groups() {
+        for o in group.options() {
+            s.push('\n');
+            match o.value {
+                Some(value) => s.push_str(&format!("    {} <{}>\n", o.flag, value)),
+                None => s.push_str(&format!("    {}\n", o.flag)),
+            }
+            let help = o.help
+                .replace("{interpreters}", &interpreters.join(", "))
+                .replace("{default}", interpreter::default().name());
+            for line in help.lines() {
+                s.push_str(&format!("        {}\n", line));
+            }
+        }
+    }
+    s
+}
@@ -1,5 +1,8 @@
 use crate::Ctx;
 use crate::EvalResult;
+use crate::{Halt, Suspended};
+use crate::Interpreter;
+use crate::trace::Event;
 use crate::Term;
 use crate::Term::*;
 use std::rc::Rc;
@@ -37,13 +40,14 @@ fn eval(
             move |_ctx: &mut Ctx| {
                 eval(Rc::clone(&f), Rc::new(
                     // cont1
-                    move |ef: Rc<Term>, _ctx: &mut Ctx| {
+                    move |ef: Rc<Term>, ctx: &mut Ctx| {
                         if let D = *ef {
+                            ctx.trace(Event::Promise { x: &x });
                             ContResult::NextStep(Box::new({
                                 let cont = Rc::clone(&cont);
                                 let x = Rc::clone(&x);
                                 move |ctx: &mut Ctx| {
-                                    cont(Rc::new(Promise(x)), ctx)
+                                    cont(Term::new(Promise(x)), ctx)
                                 }
                             }))
                         } else {
@@ -82,61 +86,71 @@ fn apply(
     if let Apply(_, _) = *x {
         panic!();
     }
+    if ctx.out_of_fuel() {
+        // resumes with this application
+        return ContResult::Finished(Err(Halt::OutOfFuel(Suspended::Cps(Box::new(
+            move |ctx: &mut Ctx| apply(f, x, ctx, cont))))));
+    }
+    ctx.steps += 1;
+    ctx.trace(Event::Apply { f: &f, x: &x });
 
     cont(match *f {
-        K => Rc::new(K1(x)),
+        K => Term::new(K1(x)),
         K1(ref y) => Rc::clone(y),
-        S => Rc::new(S1(x)),
-        S1(ref y) => Rc::new(S2(Rc::clone(y), x)),
+        S => Term::new(S1(x)),
+        S1(ref y) => Term::new(S2(Rc::clone(y), x)),
 
         S2(ref y, ref z) => {
-            return eval(Rc::new(Apply(
-                Rc::new(Apply(Rc::clone(y), Rc::clone(&x))),
-                Rc::new(Apply(Rc::clone(z), Rc::clone(&x))))), cont);
+            return eval(Term::new(Apply(
+                Term::new(Apply(Rc::clone(y), Rc::clone(&x))),
+                Term::new(Apply(Rc::clone(z), Rc::clone(&x))))), cont);
         }
 
         Print(c) => {
-            ctx.output.write_fmt(format_args!("{}", c)).unwrap();
+            if let Err(h) = ctx.write_char(c) {
+                return ContResult::Finished(Err(h));
+            }
             x
         }
         I => x,
         V => f,
         E => {
-            return ContResult::Finished(Err(x));
+            return ContResult::Finished(Err(Halt::Exit(x)));
         }
         Read => {
-            let c = ctx.input.next();
-            ctx.cur_char = c;
-            let t = match c {
-                Some(_) => Rc::new(I),
-                None => Rc::new(V),
+            let t = match ctx.read_char() {
+                Ok(Some(_)) => Term::new(I),
+                Ok(None) => Term::new(V),
+                Err(h) => return ContResult::Finished(Err(h)),
             };
-            return eval(Rc::new(Apply(x, t)), cont);
+            return eval(Term::new(Apply(x, t)), cont);
         }
         CompareRead(c) => {
             let t = match ctx.cur_char {
-                Some(cc) if cc == c => Rc::new(I),
-                _ => Rc::new(V),
+                Some(cc) if cc == c => Term::new(I),
+                _ => Term::new(V),
             };
-            return eval(Rc::new(Apply(x, t)), cont);
+            return eval(Term::new(Apply(x, t)), cont);
         }
         Reprint => {
             let t = match ctx.cur_char {
-                Some(c) => Rc::new(Print(c)),
-                None => Rc::new(V),
+                Some(c) => Term::new(Print(c)),
+                None => Term::new(V),
             };
-            return eval(Rc::new(Apply(x, t)), cont);
+            return eval(Term::new(Apply(x, t)), cont);
         }
         D => panic!("should be handled in eval"),
 
         Promise(ref f) => {
-            return eval(Rc::new(Apply(Rc::clone(f), x)), cont);
+            return eval(Term::new(Apply(Rc::clone(f), x)), cont);
         }
 
         C => {
-            return eval(Rc::new(Apply(x, Rc::new(Cont(Rc::clone(&cont))))), cont);
+            ctx.trace(Event::Capture);
+            return eval(Term::new(Apply(x, Term::new(Cont(Rc::clone(&cont))))), cont);
         }
         Cont(ref cont) => {
+            ctx.trace(Event::Invoke { x: &x });
             return cont(x, ctx);
         }
 
@@ -152,13 +166,35 @@ pub fn full_eval(term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
         ContResult::Finished(Ok(x))
     };
 
-    let mut r = ContResult::NextStep(Box::new(move |_ctx: &mut Ctx| {
+    let r = ContResult::NextStep(Box::new(move |_ctx: &mut Ctx| {
         eval(term, Rc::new(cont))
     }));
+    run(r, ctx)
+}
+
+pub fn run(mut r: ContResult, ctx: &mut Ctx) -> EvalResult {
     loop {
         match r {
-            ContResult::NextStep(step) => r = step(ctx),
+            ContResult::NextStep(step) => {
+                if let Some(halt) = ctx.check_limits() {
+                    return Err(halt(Suspended::Cps(step)));
+                }
+                r = step(ctx)
+            }
             ContResult::Finished(result) => return result,
         }
     }
 }
+
+pub struct Cps;
+
+impl Interpreter for Cps {
+    fn name(&self) -> &'static str { "cps" }
+    fn supports_call_cc(&self) -> bool { true }
+    fn supports_stepping(&self) -> bool { false }
+    fn supports_resuming(&self) -> bool { true }
+
+    fn run(&self, term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
+        full_eval(term, ctx)
+    }
+}
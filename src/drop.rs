@@ -45,9 +45,11 @@ impl Drop for Term {
     fn drop(&mut self) {
         DROP_QUEUE.with(|drop_queue| {
             let terms = &mut drop_queue.borrow_mut();
+            crate::memory::term_dropped();
             deconstruct_term(std::mem::replace(self, K), terms);
             while let Some(p) = terms.pop() {
                 if let Ok(t) = Rc::try_unwrap(p) {
+                    crate::memory::term_dropped();
                     deconstruct_term(t, terms);
                 }
             }
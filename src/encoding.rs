@@ -0,0 +1,144 @@
+// Conversion between the characters Unlambda programs read and print
+// and the bytes of program input and output, see `io::Streams`.
+
+use std::io::{self, BufRead, Write};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Each byte is a character U+0000..U+00FF, so any byte can be read
+    /// by `@` and printed by `.x`.
+    Bytes,
+    /// Characters are UTF-8 sequences.
+    #[default]
+    Utf8,
+    /// Only ASCII, anything else is an error.
+    Ascii,
+}
+
+impl Encoding {
+    /// Name as accepted by `--encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Bytes => "bytes",
+            Encoding::Utf8 => "utf8",
+            Encoding::Ascii => "ascii",
+        }
+    }
+
+    pub fn find(name: &str) -> Option<Encoding> {
+        [Encoding::Bytes, Encoding::Utf8, Encoding::Ascii].into_iter()
+            .find(|e| e.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Decodes program source.
+    pub fn decode(self, bytes: Vec<u8>) -> io::Result<String> {
+        match self {
+            Encoding::Bytes => Ok(bytes.into_iter().map(char::from).collect()),
+            Encoding::Utf8 => String::from_utf8(bytes)
+                .map_err(|e| invalid_data(format!("invalid UTF-8 at byte {}", e.utf8_error().valid_up_to()))),
+            Encoding::Ascii => match bytes.iter().position(|b| !b.is_ascii()) {
+                Some(i) => Err(invalid_data(format!("non-ASCII byte 0x{:02x} at byte {}", bytes[i], i))),
+                None => Ok(bytes.into_iter().map(char::from).collect()),
+            },
+        }
+    }
+
+    /// Reads only the bytes of one character, so `input` can be shared.
+    pub(crate) fn read(self, input: &mut dyn BufRead) -> io::Result<Option<char>> {
+        let b = match next_byte(input)? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        match self {
+            Encoding::Bytes => Ok(Some(char::from(b))),
+            Encoding::Ascii if b.is_ascii() => Ok(Some(char::from(b))),
+            Encoding::Ascii => Err(invalid_data(format!("non-ASCII byte 0x{:02x} in input", b))),
+            Encoding::Utf8 => {
+                let len = match b.leading_ones() {
+                    0 => 1,
+                    n @ 2..=4 => n as usize,
+                    _ => 0,
+                };
+                let mut buf = [b, 0, 0, 0];
+                for slot in &mut buf[1..len.max(1)] {
+                    match next_byte(input)? {
+                        Some(b) => *slot = b,
+                        None => break,
+                    }
+                }
+                match std::str::from_utf8(&buf[..len]) {
+                    Ok(s) if len > 0 => Ok(s.chars().next()),
+                    _ => Err(invalid_data(String::from("invalid UTF-8 in input"))),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn write(self, c: char, output: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Encoding::Bytes => match u8::try_from(c) {
+                Ok(b) => output.write_all(&[b]),
+                Err(_) => Err(invalid_data(format!("{:?} does not fit in a byte", c))),
+            },
+            Encoding::Utf8 => output.write_all(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Encoding::Ascii if c.is_ascii() => output.write_all(&[c as u8]),
+            Encoding::Ascii => Err(invalid_data(format!("{:?} is not ASCII", c))),
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn next_byte(input: &mut dyn BufRead) -> io::Result<Option<u8>> {
+    let b = input.fill_buf()?.first().copied();
+    if b.is_some() {
+        input.consume(1);
+    }
+    Ok(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(input: &[u8], encoding: Encoding) -> Vec<Result<char, String>> {
+        let mut input = input;
+        std::iter::from_fn(|| encoding.read(&mut input).transpose())
+            .map(|r| r.map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    fn encoded(s: &str, encoding: Encoding) -> Result<Vec<u8>, String> {
+        let mut output = Vec::new();
+        for c in s.chars() {
+            encoding.write(c, &mut output).map_err(|e| e.to_string())?;
+        }
+        Ok(output)
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decoded(b"a\xe9\xff", Encoding::Bytes), [Ok('a'), Ok('é'), Ok('ÿ')]);
+        assert_eq!(decoded("aé€😀".as_bytes(), Encoding::Utf8), [Ok('a'), Ok('é'), Ok('€'), Ok('😀')]);
+        assert_eq!(decoded(b"\xe9a", Encoding::Utf8), [Err("invalid UTF-8 in input".to_string())]);
+        assert_eq!(decoded(b"\x80", Encoding::Utf8), [Err("invalid UTF-8 in input".to_string())]);
+        assert_eq!(decoded(b"a\xc3", Encoding::Utf8)[1], Err("invalid UTF-8 in input".to_string()));
+        assert_eq!(decoded(b"a\xe9", Encoding::Ascii),
+                   [Ok('a'), Err("non-ASCII byte 0xe9 in input".to_string())]);
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(encoded("aé\u{ff}", Encoding::Bytes), Ok(b"a\xe9\xff".to_vec()));
+        assert_eq!(encoded("€", Encoding::Bytes), Err("'€' does not fit in a byte".to_string()));
+        assert_eq!(encoded("aé", Encoding::Utf8), Ok("aé".as_bytes().to_vec()));
+        assert_eq!(encoded("aé", Encoding::Ascii), Err("'é' is not ASCII".to_string()));
+        assert_eq!(Encoding::Bytes.decode(b".\xe9".to_vec()).unwrap(), ".é");
+        assert!(Encoding::Utf8.decode(b".\xe9".to_vec()).is_err());
+        assert_eq!(Encoding::Ascii.decode(b"`.ai".to_vec()).unwrap(), "`.ai");
+        assert_eq!(Encoding::Ascii.decode("`.éi".as_bytes().to_vec()).unwrap_err().to_string(),
+                   "non-ASCII byte 0xc3 at byte 2");
+    }
+}
@@ -0,0 +1,67 @@
+use crate::Ctx;
+use crate::EvalResult;
+use crate::Term;
+use crate::{metacircular, cps, small_step};
+use std::rc::Rc;
+
+/// Common interface of all evaluation engines.
+///
+/// Engines differ in what they support, so callers are expected
+/// to query the capabilities (or just call `check()`) before `eval()`.
+pub trait Interpreter: Sync {
+    /// Short lowercase name, as accepted by `--interpreter`.
+    fn name(&self) -> &'static str;
+
+    /// Whether `c` (call/cc) can be used in programs.
+    fn supports_call_cc(&self) -> bool;
+
+    /// Whether evaluation can be driven one step at a time,
+    /// with `small_step::Machine`.
+    fn supports_stepping(&self) -> bool;
+
+    /// Whether evaluation stopped by a limit or cancellation
+    /// can be continued with `Suspended::resume()`.
+    fn supports_resuming(&self) -> bool;
+
+    /// Panics if the program uses something the interpreter does not
+    /// support, call `check()` first.
+    fn eval(&self, term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
+        // Only interpreters with explicit continuations report depth,
+        // don't report one left by another interpreter sharing the context.
+        ctx.depth = None;
+        self.run(term, ctx)
+    }
+
+    /// Evaluation proper, to be implemented but not called:
+    /// callers go through `eval()`, which resets the state
+    /// another interpreter may have left in `ctx`.
+    fn run(&self, term: Rc<Term>, ctx: &mut Ctx) -> EvalResult;
+
+    /// Returns an error message if the interpreter can't run the program.
+    fn check(&self, program: &Term) -> Result<(), String> {
+        if !self.supports_call_cc() && metacircular::contains_c(program) {
+            return Err(format!("{} interpreter does not support call/cc", self.name()));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for dyn Interpreter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// All available interpreters, the default one goes last.
+pub fn all() -> &'static [&'static dyn Interpreter] {
+    &[&metacircular::MetaCircular, &cps::Cps, &small_step::SmallStep]
+}
+
+pub fn default() -> &'static dyn Interpreter {
+    *all().last().unwrap()
+}
+
+/// Case-insensitive lookup by name.
+pub fn find(name: &str) -> Option<&'static dyn Interpreter> {
+    all().iter().copied().find(|i| i.name().eq_ignore_ascii_case(name))
+}
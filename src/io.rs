@@ -0,0 +1,473 @@
+// Devices programs read from and print to, see `Ctx::with_io()`.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use crate::Encoding;
+
+/// Character device used by `@` and `.x`.
+pub trait Io {
+    /// Next input character, None on EOF.
+    fn read_char(&mut self) -> io::Result<Option<char>>;
+
+    fn write_char(&mut self, c: char) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the next `read_char()` would return None,
+    /// may block until input is available.
+    fn is_eof(&mut self) -> io::Result<bool>;
+}
+
+impl<T: Io + ?Sized> Io for &mut T {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        (**self).read_char()
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        (**self).write_char(c)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn is_eof(&mut self) -> io::Result<bool> {
+        (**self).is_eof()
+    }
+}
+
+/// Byte streams, characters are converted according to the encoding.
+pub struct Streams<R, W> {
+    input: R,
+    output: W,
+    encoding: Encoding,
+}
+
+pub type Stdio = Streams<io::StdinLock<'static>, io::Stdout>;
+pub type Files = Streams<BufReader<File>, BufWriter<File>>;
+
+impl<R: BufRead, W: Write> Streams<R, W> {
+    pub fn new(input: R, output: W, encoding: Encoding) -> Self {
+        Streams { input, output, encoding }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+}
+
+impl Stdio {
+    pub fn stdio(encoding: Encoding) -> Self {
+        Streams::new(io::stdin().lock(), io::stdout(), encoding)
+    }
+}
+
+impl Files {
+    /// Output is buffered, call `flush()` when done.
+    pub fn open(input: &Path, output: &Path, encoding: Encoding) -> io::Result<Self> {
+        let input = BufReader::new(File::open(input)?);
+        let output = BufWriter::new(File::create(output)?);
+        Ok(Streams::new(input, output, encoding))
+    }
+}
+
+impl<R: BufRead, W: Write> Io for Streams<R, W> {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        self.encoding.read(&mut self.input)
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        self.encoding.write(c, &mut self.output)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    fn is_eof(&mut self) -> io::Result<bool> {
+        Ok(self.input.fill_buf()?.is_empty())
+    }
+}
+
+/// In-memory input and output.
+#[derive(Clone, Debug, Default)]
+pub struct Buffers {
+    input: VecDeque<char>,
+    output: String,
+}
+
+impl Buffers {
+    pub fn new(input: &str) -> Self {
+        Buffers {
+            input: input.chars().collect(),
+            output: String::new(),
+        }
+    }
+
+    /// Everything printed so far.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Input not read yet.
+    pub fn remaining_input(&self) -> String {
+        self.input.iter().collect()
+    }
+
+    /// Makes more input available, e.g. between evaluations.
+    pub fn push_input(&mut self, s: &str) {
+        self.input.extend(s.chars());
+    }
+}
+
+impl Io for Buffers {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn is_eof(&mut self) -> io::Result<bool> {
+        Ok(self.input.is_empty())
+    }
+}
+
+/// Device for hosts that receive input as it arrives, e.g. from UI events.
+///
+/// Reading when no input is available fails with `ErrorKind::WouldBlock`,
+/// on which the small-step interpreter suspends with `Halt::NeedsInput`,
+/// until `close()` turns it into EOF.
+#[derive(Clone, Debug, Default)]
+pub struct Queue {
+    input: VecDeque<char>,
+    closed: bool,
+    output: String,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push_input(&mut self, s: &str) {
+        self.input.extend(s.chars());
+    }
+
+    /// Reads will return EOF once the input pushed so far is consumed.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Returns what was printed since the last call.
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Io for Queue {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        match self.input.pop_front() {
+            Some(c) => Ok(Some(c)),
+            None if self.closed => Ok(None),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn is_eof(&mut self) -> io::Result<bool> {
+        match self.input.front() {
+            Some(_) => Ok(false),
+            None if self.closed => Ok(true),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// Makes the input of a device start over instead of ending,
+/// like `Iterator::cycle()` (an empty input still ends).
+pub struct Repeat<T> {
+    inner: T,
+    read: Vec<char>,
+    // position in `read` once the input of `inner` has ended
+    replay: Option<usize>,
+}
+
+impl<T: Io> Repeat<T> {
+    pub fn new(inner: T) -> Self {
+        Repeat { inner, read: Vec::new(), replay: None }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Io> Io for Repeat<T> {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        if self.replay.is_none() {
+            match self.inner.read_char()? {
+                Some(c) => {
+                    self.read.push(c);
+                    return Ok(Some(c));
+                }
+                None => self.replay = Some(0),
+            }
+        }
+        if self.read.is_empty() {
+            return Ok(None);
+        }
+        let i = self.replay.as_mut().unwrap();
+        let c = self.read[*i];
+        *i = (*i + 1) % self.read.len();
+        Ok(Some(c))
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        self.inner.write_char(c)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn is_eof(&mut self) -> io::Result<bool> {
+        if !self.read.is_empty() {
+            return Ok(false);
+        }
+        match self.replay {
+            Some(_) => Ok(true),
+            None => self.inner.is_eof(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Action {
+    Input(char),
+    Output(char),
+    Eof,
+    Fail(ErrorKind),
+}
+
+/// Mock device for tests: plays a dialogue in which input becomes
+/// available and output is expected in the given order.
+/// Operations not matching the script fail.
+#[derive(Debug, Default)]
+pub struct Scripted {
+    script: VecDeque<Action>,
+}
+
+impl Scripted {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Characters to be read.
+    pub fn input(mut self, s: &str) -> Self {
+        self.script.extend(s.chars().map(Action::Input));
+        self
+    }
+
+    /// Characters the program has to print.
+    pub fn output(mut self, s: &str) -> Self {
+        self.script.extend(s.chars().map(Action::Output));
+        self
+    }
+
+    /// One read returning EOF, after the end of the script every read does.
+    pub fn eof(mut self) -> Self {
+        self.script.push_back(Action::Eof);
+        self
+    }
+
+    /// The next read or write fails.
+    pub fn fail(mut self, kind: ErrorKind) -> Self {
+        self.script.push_back(Action::Fail(kind));
+        self
+    }
+
+    /// Whether the whole script was played.
+    pub fn is_finished(&self) -> bool {
+        self.script.is_empty()
+    }
+
+    fn unexpected(&self, operation: String) -> io::Error {
+        let expected = match self.script.front() {
+            Some(Action::Input(_)) => String::from("input"),
+            Some(Action::Output(c)) => format!("output {:?}", c),
+            Some(Action::Eof) => String::from("EOF"),
+            Some(Action::Fail(_)) => String::from("failure"),
+            None => String::from("the end"),
+        };
+        io::Error::other(format!("unexpected {}, the script expects {}", operation, expected))
+    }
+}
+
+impl Io for Scripted {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        match self.script.front() {
+            Some(&Action::Input(c)) => {
+                self.script.pop_front();
+                Ok(Some(c))
+            }
+            Some(Action::Eof) => {
+                self.script.pop_front();
+                Ok(None)
+            }
+            None => Ok(None),
+            Some(&Action::Fail(kind)) => {
+                self.script.pop_front();
+                Err(kind.into())
+            }
+            Some(Action::Output(_)) => Err(self.unexpected(String::from("read"))),
+        }
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        match self.script.front() {
+            Some(&Action::Output(expected)) if expected == c => {
+                self.script.pop_front();
+                Ok(())
+            }
+            Some(&Action::Fail(kind)) => {
+                self.script.pop_front();
+                Err(kind.into())
+            }
+            _ => Err(self.unexpected(format!("output {:?}", c))),
+        }
+    }
+
+    fn is_eof(&mut self) -> io::Result<bool> {
+        let next_read = self.script.iter().find(|a| !matches!(a, Action::Output(_)));
+        Ok(!matches!(next_read, Some(Action::Input(_))))
+    }
+}
+
+// Adapts the arguments of `Ctx::new()`.
+pub(crate) struct Chars<'a> {
+    input: std::iter::Peekable<&'a mut dyn Iterator<Item=char>>,
+    output: &'a mut dyn Write,
+}
+
+impl<'a> Chars<'a> {
+    pub(crate) fn new(input: &'a mut dyn Iterator<Item=char>, output: &'a mut dyn Write) -> Self {
+        Chars { input: input.peekable(), output }
+    }
+}
+
+impl Io for Chars<'_> {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        Ok(self.input.next())
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        Encoding::Utf8.write(c, self.output)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    fn is_eof(&mut self) -> io::Result<bool> {
+        Ok(self.input.peek().is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams() {
+        let mut io = Streams::new("aé".as_bytes(), Vec::new(), Encoding::Bytes);
+        assert!(!io.is_eof().unwrap());
+        assert_eq!(io.read_char().unwrap(), Some('a'));
+        assert_eq!(io.read_char().unwrap(), Some('\u{c3}'));
+        assert_eq!(io.read_char().unwrap(), Some('\u{a9}'));
+        assert!(io.is_eof().unwrap());
+        assert_eq!(io.read_char().unwrap(), None);
+        io.write_char('\u{ff}').unwrap();
+        assert_eq!(io.into_inner().1, b"\xff");
+
+        let dir = std::env::temp_dir();
+        let (input, output) = (dir.join("unlambda-io-in"), dir.join("unlambda-io-out"));
+        std::fs::write(&input, "é").unwrap();
+        let mut io = Files::open(&input, &output, Encoding::Utf8).unwrap();
+        let c = io.read_char().unwrap().unwrap();
+        io.write_char(c).unwrap();
+        io.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "é");
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn buffers() {
+        let mut io = Buffers::new("ab");
+        assert_eq!(io.read_char().unwrap(), Some('a'));
+        io.write_char('x').unwrap();
+        io.push_input("c");
+        assert_eq!(io.remaining_input(), "bc");
+        assert_eq!(io.output(), "x");
+        assert!(!io.is_eof().unwrap());
+    }
+
+    #[test]
+    fn queue() {
+        let mut io = Queue::new();
+        assert_eq!(io.read_char().unwrap_err().kind(), ErrorKind::WouldBlock);
+        io.push_input("a");
+        assert!(!io.is_eof().unwrap());
+        assert_eq!(io.read_char().unwrap(), Some('a'));
+        assert_eq!(io.is_eof().unwrap_err().kind(), ErrorKind::WouldBlock);
+        io.close();
+        assert!(io.is_eof().unwrap());
+        assert_eq!(io.read_char().unwrap(), None);
+        io.write_char('x').unwrap();
+        assert_eq!(io.take_output(), "x");
+        assert_eq!(io.take_output(), "");
+    }
+
+    #[test]
+    fn repeat() {
+        let mut io = Repeat::new(Buffers::new("ab"));
+        let read: Vec<_> = (0..5).map(|_| io.read_char().unwrap().unwrap()).collect();
+        assert_eq!(read, ['a', 'b', 'a', 'b', 'a']);
+        assert!(!io.is_eof().unwrap());
+
+        let mut io = Repeat::new(Buffers::new(""));
+        assert!(io.is_eof().unwrap());
+        assert_eq!(io.read_char().unwrap(), None);
+        assert_eq!(io.read_char().unwrap(), None);
+        io.write_char('x').unwrap();
+        assert_eq!(io.into_inner().output(), "x");
+    }
+
+    #[test]
+    fn scripted() {
+        let mut io = Scripted::new().output("?").input("a").eof().fail(ErrorKind::BrokenPipe);
+        assert_eq!(io.read_char().unwrap_err().to_string(),
+                   "unexpected read, the script expects output '?'");
+        io.write_char('?').unwrap();
+        assert!(!io.is_eof().unwrap());
+        assert_eq!(io.read_char().unwrap(), Some('a'));
+        assert!(io.is_eof().unwrap());
+        assert_eq!(io.read_char().unwrap(), None);
+        assert_eq!(io.write_char('!').unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert!(io.is_finished());
+        assert_eq!(io.write_char('!').unwrap_err().to_string(),
+                   "unexpected output '!', the script expects the end");
+        assert_eq!(io.read_char().unwrap(), None);
+    }
+}
@@ -0,0 +1,630 @@
+// Lambda calculus front-end, compiled to combinators by bracket abstraction.
+//
+//     (\f x. f (f x)) (\c. .a c) i     # prints "aa"
+//
+// `\x y. M` (or `λ`) abstracts over variables, application is juxtaposition
+// (or Unlambda-style `` `MN ``), `#` starts a comment. Unbound single letters
+// and `.x`, `?x`, `@`, `|` are Unlambda builtins.
+//
+// Evaluation is call-by-value as in Unlambda, so the body of `\x. M` must
+// be evaluated each time the function is applied, never earlier:
+// - an application not mentioning x is delayed with `` `d`kM `` instead of `` `kM ``,
+// - combinators that evaluate their operands early (B, C, η-reduction) are only
+//   used when the operands are values, whose evaluation has no effects.
+// `d` needs no special care: S and B build `` `Fx `` as an operand, which is not
+// evaluated when the operator turns out to be `d`, just like in the source.
+
+use std::rc::Rc;
+use crate::Term;
+use crate::Term::*;
+use crate::parser::{excerpt, line_col};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Abstraction {
+    /// Only S, K and I.
+    Naive,
+    /// Also η-reduction and Turner's B and C (encoded in SKI),
+    /// produces much smaller terms.
+    Turner,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LambdaErrorKind {
+    UnexpectedEof,
+    Unexpected(char),
+    /// Not a bound variable nor a builtin.
+    Unbound(String),
+    /// `\` not followed by a variable.
+    MissingParameter,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LambdaError {
+    pub kind: LambdaErrorKind,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for LambdaErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            LambdaErrorKind::UnexpectedEof => write!(f, "unexpected EOF"),
+            LambdaErrorKind::Unexpected(c) => write!(f, "unexpected {:?}", c),
+            LambdaErrorKind::Unbound(ref name) => write!(f, "unbound variable {}", name),
+            LambdaErrorKind::MissingParameter => write!(f, "expected a variable"),
+        }
+    }
+}
+
+impl std::fmt::Display for LambdaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for LambdaError {}
+
+impl LambdaError {
+    /// Human-readable message with the source line.
+    pub fn render(&self, s: &str) -> String {
+        format!("error: {}\n{}", self.kind, excerpt(s, self.offset, "here"))
+    }
+}
+
+// Combinator expressions, with variables not yet abstracted.
+#[derive(Debug)]
+enum Ski {
+    Var(String),
+    Const(Rc<Term>),
+    // `value`: whether evaluating it has no effects (and always terminates).
+    App { f: Rc<Ski>, a: Rc<Ski>, value: bool },
+}
+
+fn app(f: Rc<Ski>, a: Rc<Ski>) -> Rc<Ski> {
+    let value = match *f {
+        Ski::Const(ref t) => match **t {
+            D => true,
+            K | S => a.is_value(),
+            _ => false,
+        },
+        Ski::App { f: ref ff, a: ref fa, .. } => {
+            matches!(**ff, Ski::Const(ref t) if matches!(**t, S))
+                && fa.is_value() && a.is_value()
+        }
+        Ski::Var(_) => false,
+    };
+    Rc::new(Ski::App { f, a, value })
+}
+
+fn constant(t: Term) -> Rc<Ski> {
+    Rc::new(Ski::Const(Term::new(t)))
+}
+
+// B f g x = f (g x)
+fn b() -> Rc<Ski> {
+    app(app(constant(S), app(constant(K), constant(S))), constant(K))
+}
+
+// C f g x = f x g
+fn c() -> Rc<Ski> {
+    let s_ks_k = app(app(constant(S), app(constant(K), constant(S))), constant(K));
+    let inner = app(app(constant(S), app(constant(K), s_ks_k)), constant(S));
+    app(app(constant(S), inner), app(constant(K), constant(K)))
+}
+
+// Default recursive drop overflows stack on deep source.
+impl Drop for Ski {
+    fn drop(&mut self) {
+        let mut todo = Vec::new();
+        self.take_children(&mut todo);
+        while let Some(mut t) = todo.pop() {
+            t.take_children(&mut todo);
+        }
+    }
+}
+
+impl Ski {
+    // Moves out the children not shared with other trees.
+    fn take_children(&mut self, todo: &mut Vec<Ski>) {
+        if let Ski::App { ref mut f, ref mut a, .. } = *self {
+            for child in [f, a] {
+                if let Some(child) = Rc::get_mut(child) {
+                    todo.push(std::mem::replace(child, Ski::Var(String::new())));
+                }
+            }
+        }
+    }
+
+    fn is_value(&self) -> bool {
+        match *self {
+            Ski::App { value, .. } => value,
+            // variables are bound to values
+            Ski::Var(_) | Ski::Const(_) => true,
+        }
+    }
+
+    fn is_d(&self) -> bool {
+        matches!(*self, Ski::Const(ref t) if matches!(**t, D))
+    }
+
+    fn to_term(&self) -> Rc<Term> {
+        // post-order, with the terms of finished subtrees on a stack
+        let mut todo = vec![(self, false)];
+        let mut done: Vec<Rc<Term>> = Vec::new();
+        while let Some((t, visited)) = todo.pop() {
+            match *t {
+                Ski::Var(_) => panic!("all variables should be abstracted"),
+                Ski::Const(ref t) => done.push(Rc::clone(t)),
+                Ski::App { ref f, ref a, .. } if !visited => {
+                    todo.push((t, true));
+                    todo.push((a, false));
+                    todo.push((f, false));
+                }
+                Ski::App { .. } => {
+                    let a = done.pop().unwrap();
+                    let f = done.pop().unwrap();
+                    done.push(Term::new(Apply(f, a)));
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+}
+
+// [x]m where x does not occur in m
+fn constant_fn(m: &Rc<Ski>) -> Rc<Ski> {
+    if m.is_value() {
+        app(constant(K), Rc::clone(m))
+    } else {
+        app(constant(D), app(constant(K), Rc::clone(m)))
+    }
+}
+
+// [x]m
+// Post-order, so whether x occurs in a subterm is known from its children
+// instead of being searched for again at each level.
+fn abstract_var(x: &str, m: &Rc<Ski>, algorithm: Abstraction) -> Rc<Ski> {
+    let mut todo = vec![(m, false)];
+    // [x] of finished subterms, None where x does not occur
+    let mut done: Vec<Option<Rc<Ski>>> = Vec::new();
+    while let Some((t, visited)) = todo.pop() {
+        match **t {
+            Ski::Var(ref y) => done.push((x == y).then(|| constant(I))),
+            Ski::Const(_) => done.push(None),
+            Ski::App { ref f, ref a, .. } if !visited => {
+                todo.push((t, true));
+                todo.push((a, false));
+                todo.push((f, false));
+            }
+            Ski::App { ref f, ref a, .. } => {
+                let xa = done.pop().unwrap();
+                let xf = done.pop().unwrap();
+                done.push(match (xf, xa) {
+                    (None, None) => None,
+                    (xf, xa) => Some(abstract_app(f, a, xf, xa, algorithm)),
+                });
+            }
+        }
+    }
+    done.pop().unwrap().unwrap_or_else(|| constant_fn(m))
+}
+
+// [x]`fa given [x]f and [x]a, at least one of which mentions x
+fn abstract_app(
+    f: &Rc<Ski>, a: &Rc<Ski>,
+    xf: Option<Rc<Ski>>, xa: Option<Rc<Ski>>,
+    algorithm: Abstraction,
+) -> Rc<Ski> {
+    let turner = algorithm == Abstraction::Turner;
+    match (xf, xa) {
+        (None, Some(xa)) if turner && f.is_value() => {
+            if matches!(**a, Ski::Var(_)) && !f.is_d() {
+                Rc::clone(f)
+            } else {
+                app(app(b(), Rc::clone(f)), xa)
+            }
+        }
+        (Some(xf), None) if turner && a.is_value() => app(app(c(), xf), Rc::clone(a)),
+        (xf, xa) => app(
+            app(constant(S), xf.unwrap_or_else(|| constant_fn(f))),
+            xa.unwrap_or_else(|| constant_fn(a)),
+        ),
+    }
+}
+
+enum Expr {
+    Var(String),
+    Const(Term),
+    App(Box<Expr>, Box<Expr>),
+    Lambda(String, Box<Expr>),
+}
+
+// Default recursive drop overflows stack on deep source.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut todo = Vec::new();
+        self.take_children(&mut todo);
+        while let Some(mut e) = todo.pop() {
+            e.take_children(&mut todo);
+        }
+    }
+}
+
+impl Expr {
+    fn take(&mut self) -> Expr {
+        std::mem::replace(self, Expr::Var(String::new()))
+    }
+
+    fn take_children(&mut self, todo: &mut Vec<Expr>) {
+        match *self {
+            Expr::App(ref mut f, ref mut a) => {
+                todo.push(f.take());
+                todo.push(a.take());
+            }
+            Expr::Lambda(_, ref mut body) => todo.push(body.take()),
+            Expr::Var(_) | Expr::Const(_) => {}
+        }
+    }
+}
+
+fn compile_expr(e: Expr, algorithm: Abstraction) -> Rc<Ski> {
+    enum Step {
+        Enter(Expr),
+        App,
+        Lambda(String),
+    }
+    // post-order, with the compiled subexpressions on a stack
+    let mut todo = vec![Step::Enter(e)];
+    let mut done = Vec::new();
+    while let Some(step) = todo.pop() {
+        match step {
+            Step::Enter(mut e) => match e {
+                Expr::Var(ref mut x) => done.push(Rc::new(Ski::Var(std::mem::take(x)))),
+                Expr::Const(ref mut t) => done.push(constant(std::mem::replace(t, I))),
+                Expr::App(ref mut f, ref mut a) => {
+                    todo.push(Step::App);
+                    todo.push(Step::Enter(a.take()));
+                    todo.push(Step::Enter(f.take()));
+                }
+                Expr::Lambda(ref mut x, ref mut body) => {
+                    todo.push(Step::Lambda(std::mem::take(x)));
+                    todo.push(Step::Enter(body.take()));
+                }
+            },
+            Step::App => {
+                let a = done.pop().unwrap();
+                let f = done.pop().unwrap();
+                done.push(app(f, a));
+            }
+            Step::Lambda(x) => {
+                let body = done.pop().unwrap();
+                done.push(abstract_var(&x, &body, algorithm));
+            }
+        }
+    }
+    done.pop().unwrap()
+}
+
+// Unfinished constructs, innermost on top of the parser's stack.
+enum Frame {
+    // Applications extend as far as possible.
+    Seq { result: Option<Expr>, end: End },
+    // `` `FA ``, with F once it is parsed
+    Tick(Option<Expr>),
+}
+
+// Where a sequence ends.
+enum End {
+    Eof,
+    Paren,
+    // So does the body of a lambda, together with the enclosing sequence.
+    Lambda(Vec<String>),
+}
+
+// Hands a parsed expression to the constructs it completes.
+fn finish(stack: &mut Vec<Frame>, mut e: Expr) {
+    loop {
+        match stack.last_mut().unwrap() {
+            Frame::Seq { result, .. } => {
+                *result = Some(match result.take() {
+                    Some(f) => Expr::App(Box::new(f), Box::new(e)),
+                    None => e,
+                });
+                return;
+            }
+            Frame::Tick(f @ None) => {
+                *f = Some(e);
+                return;
+            }
+            Frame::Tick(Some(_)) => {
+                let Some(Frame::Tick(Some(f))) = stack.pop() else { unreachable!() };
+                e = Expr::App(Box::new(f), Box::new(e));
+            }
+        }
+    }
+}
+
+struct Parser<'s> {
+    s: &'s str,
+    pos: usize,
+    // innermost last
+    bound: Vec<String>,
+}
+
+impl Parser<'_> {
+    fn error(&self, kind: LambdaErrorKind, offset: usize) -> LambdaError {
+        let (line, column) = line_col(self.s, offset);
+        LambdaError { kind, offset, line, column }
+    }
+
+    fn unexpected(&self) -> LambdaError {
+        match self.peek() {
+            Some(c) => self.error(LambdaErrorKind::Unexpected(c), self.pos),
+            None => self.error(LambdaErrorKind::UnexpectedEof, self.pos),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_blank(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.bump().is_some_and(|c| c != '\n') {}
+            } else if c.is_whitespace() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        let start = self.pos;
+        if !self.peek().is_some_and(|c| c.is_alphabetic() || c == '_') {
+            return None;
+        }
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '\'') {
+            self.bump();
+        }
+        Some(self.s[start..self.pos].to_string())
+    }
+
+    // Nested constructs are kept on a stack rather than recursed into,
+    // source can be arbitrarily deep.
+    fn expr(&mut self) -> Result<Expr, LambdaError> {
+        let mut stack = vec![Frame::Seq { result: None, end: End::Eof }];
+        loop {
+            let e = match *stack.last().unwrap() {
+                Frame::Seq { .. } => {
+                    self.skip_blank();
+                    match self.peek() {
+                        None | Some(')') => {
+                            let Some(Frame::Seq { result, end }) = stack.pop() else {
+                                unreachable!()
+                            };
+                            let e = result.ok_or_else(|| self.unexpected())?;
+                            match end {
+                                End::Eof => return Ok(e),
+                                End::Paren => {
+                                    if self.peek() != Some(')') {
+                                        return Err(self.unexpected());
+                                    }
+                                    self.bump();
+                                    e
+                                }
+                                End::Lambda(params) => {
+                                    self.bound.truncate(self.bound.len() - params.len());
+                                    params.into_iter().rev()
+                                        .fold(e, |e, x| Expr::Lambda(x, Box::new(e)))
+                                }
+                            }
+                        }
+                        Some('\\' | 'λ') => {
+                            let params = self.params()?;
+                            self.bound.extend(params.iter().cloned());
+                            stack.push(Frame::Seq { result: None, end: End::Lambda(params) });
+                            continue;
+                        }
+                        _ => match self.atom(&mut stack)? {
+                            Some(e) => e,
+                            None => continue,
+                        },
+                    }
+                }
+                Frame::Tick(_) => match self.atom(&mut stack)? {
+                    Some(e) => e,
+                    None => continue,
+                },
+            };
+            finish(&mut stack, e);
+        }
+    }
+
+    // `\x y.`
+    fn params(&mut self) -> Result<Vec<String>, LambdaError> {
+        self.bump();
+        let mut params = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some('.') {
+                self.bump();
+                break;
+            }
+            match self.ident() {
+                Some(x) => params.push(x),
+                None if params.is_empty() => {
+                    return Err(self.error(LambdaErrorKind::MissingParameter, self.pos));
+                }
+                None => return Err(self.unexpected()),
+            }
+        }
+        if params.is_empty() {
+            return Err(self.error(LambdaErrorKind::MissingParameter, self.pos - 1));
+        }
+        Ok(params)
+    }
+
+    // None if it opens a construct, pushed onto the stack.
+    fn atom(&mut self, stack: &mut Vec<Frame>) -> Result<Option<Expr>, LambdaError> {
+        self.skip_blank();
+        let start = self.pos;
+        if let Some(name) = self.ident() {
+            if self.bound.contains(&name) {
+                return Ok(Some(Expr::Var(name)));
+            }
+            return Ok(Some(Expr::Const(match name.as_str() {
+                "k" => K,
+                "s" => S,
+                "i" => I,
+                "v" => V,
+                "d" => D,
+                "c" => C,
+                "e" => E,
+                "r" => Print('\n'),
+                _ => return Err(self.error(LambdaErrorKind::Unbound(name), start)),
+            })));
+        }
+        let t = match self.bump() {
+            Some('(') => {
+                stack.push(Frame::Seq { result: None, end: End::Paren });
+                return Ok(None);
+            }
+            Some('`') => {
+                stack.push(Frame::Tick(None));
+                return Ok(None);
+            }
+            Some('.') => Print(self.bump().ok_or_else(|| self.unexpected())?),
+            Some('?') => CompareRead(self.bump().ok_or_else(|| self.unexpected())?),
+            Some('@') => Read,
+            Some('|') => Reprint,
+            _ => {
+                self.pos = start;
+                return Err(self.unexpected());
+            }
+        };
+        Ok(Some(Expr::Const(t)))
+    }
+}
+
+/// Compiles lambda calculus source to a closed combinator term.
+pub fn compile(s: &str, algorithm: Abstraction) -> Result<Rc<Term>, LambdaError> {
+    let mut p = Parser { s, pos: 0, bound: Vec::new() };
+    let e = p.expr()?;
+    if p.pos < s.len() {
+        return Err(p.unexpected());
+    }
+    Ok(compile_expr(e, algorithm).to_term())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Abstraction::*;
+    use super::LambdaErrorKind::*;
+    use crate::{interpreter, Ctx, Halt, Interpreter};
+    use crate::small_step::SmallStep;
+
+    fn compiled(s: &str, algorithm: Abstraction) -> String {
+        compile(s, algorithm).unwrap().to_string()
+    }
+
+    #[test]
+    fn abstraction() {
+        assert_eq!(compiled("\\x. x", Naive), "i");
+        assert_eq!(compiled("\\x y. x", Naive), "``s`kki");
+        assert_eq!(compiled("\\x y. x", Turner), "k");
+        assert_eq!(compiled("λf x. f x", Turner), "i");
+        assert_eq!(compiled("\\x. `.a x", Turner), ".a");
+        // d would delay the argument of the function, while the function does not
+        assert_eq!(compiled("\\x. d x", Turner), "````s`kskdi");
+        // .a must not be printed until the function is applied
+        assert_eq!(compiled("\\x. .a i", Turner), "`d`k`.ai");
+        assert_eq!(compiled("\\x. x k", Turner), "````s``s`k``s`ksks`kkik");
+        assert_eq!(compiled("\\x. s (k x)", Turner), "````s`ksksk");
+    }
+
+    fn run(program: &str, algorithm: Abstraction) -> String {
+        let program = compile(program, algorithm).unwrap();
+        let mut outputs = Vec::new();
+        for &interp in interpreter::all() {
+            if interp.check(&program).is_err() {
+                continue;
+            }
+            let mut output = Vec::new();
+            let mut input = "".chars();
+            let mut ctx = Ctx::new(&mut output, &mut input);
+            match interp.eval(Rc::clone(&program), &mut ctx) {
+                Ok(_) | Err(Halt::Exit(_)) => {}
+                Err(h) => panic!("{:?}", h),
+            }
+            drop(ctx);
+            outputs.push(String::from_utf8(output).unwrap());
+        }
+        assert!(outputs.windows(2).all(|w| w[0] == w[1]));
+        outputs.pop().unwrap()
+    }
+
+    #[test]
+    fn evaluation() {
+        for algorithm in [Naive, Turner] {
+            assert_eq!(run("(\\f x. f (f x)) (\\c. .a c) i", algorithm), "aa");
+            assert_eq!(run("(\\f x. f (f x)) .a i", algorithm), "aa");
+            // effects happen when the function is applied, each time
+            assert_eq!(run("\\x. .a i", algorithm), "");
+            assert_eq!(run("(\\f. f (f v)) (\\x. .a i)", algorithm), "aa");
+            assert_eq!(run("(\\x y. .a y) .b", algorithm), "");
+            // and in the same order as without abstraction
+            assert_eq!(run("(\\x. (.a x) (.b x)) i", algorithm), "ab");
+            assert_eq!(run("(\\x. (.a i) (.b x)) i", algorithm), "ab");
+            assert_eq!(run("(\\x. (.a x) (.b i)) i", algorithm), "ab");
+            // d delays its operand, even under abstraction
+            assert_eq!(run("(\\x. d (.a x)) i", algorithm), "");
+            assert_eq!(run("(\\x. d (.a x)) i .b", algorithm), "a");
+            assert_eq!(run("(\\x. d (.a i)) i", algorithm), "");
+            assert_eq!(run("(\\f. f (.a i)) d", algorithm), "");
+            // call/cc
+            assert_eq!(run("(\\x. .b (c (\\k. .a (k x)))) i", algorithm), "b");
+            assert_eq!(run("(\\f x. f x) e .a .b", algorithm), "");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let error = |s| compile(s, Turner).unwrap_err();
+        assert_eq!(error("\\x. y").kind, Unbound("y".to_string()));
+        assert_eq!((error("\\x. y").line, error("\\x. y").column), (1, 5));
+        assert_eq!(error("\\. x").kind, MissingParameter);
+        assert_eq!(error("\\x").kind, UnexpectedEof);
+        assert_eq!(error("(i").kind, UnexpectedEof);
+        assert_eq!(error("i)").kind, Unexpected(')'));
+        assert_eq!(error("").kind, UnexpectedEof);
+        assert_eq!(error("k % s").to_string(), "1:3: unexpected '%'");
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let n = 200000;
+        let long = "i ".repeat(n);
+        assert_eq!(compiled(&long, Turner).len(), 2 * n - 1);
+        assert_eq!(compiled(&format!("{}i{}", "(".repeat(n / 2), ")".repeat(n / 2)), Naive), "i");
+        assert_eq!(compiled(&format!("{}{}", "`".repeat(n - 1), long), Turner).len(), 2 * n - 1);
+        // a long body under abstraction, too deep for the recursive interpreters
+        let body = format!("\\x. {}x{}", "(.a ".repeat(n / 2), ")".repeat(n / 2));
+        let program = compile(&format!("({}) i", body), Turner).unwrap();
+        let mut output = Vec::new();
+        let mut input = "".chars();
+        SmallStep.eval(program, &mut Ctx::new(&mut output, &mut input)).unwrap();
+        assert_eq!(output, "a".repeat(n / 2).into_bytes());
+        assert_eq!(compile(&format!("{}%", long), Turner).unwrap_err().kind, Unexpected('%'));
+        assert_eq!(compile(&"(".repeat(n), Turner).unwrap_err().kind, UnexpectedEof);
+    }
+}
@@ -0,0 +1,381 @@
+//! Interpreters for the [Unlambda](http://www.madore.org/~david/programs/unlambda/)
+//! programming language.
+//!
+//! A program is parsed into a [`Term`] tree with [`parse`] and then
+//! evaluated by one of the engines implementing [`Interpreter`]
+//! (see [`interpreter::all()`]).
+//! All program I/O goes through a [`Ctx`], which reads and prints
+//! characters through an [`io::Io`] device.
+//!
+//! ```
+//! use unlambda::{parse, Ctx, Interpreter};
+//! use unlambda::small_step::SmallStep;
+//!
+//! let program = parse("`.a``ks.b").unwrap();
+//! let mut output = Vec::new();
+//! let mut input = "".chars();
+//! let mut ctx = Ctx::new(&mut output, &mut input);
+//! let result = SmallStep.eval(program, &mut ctx).unwrap();
+//! assert_eq!(result.to_string(), "s");
+//! drop(ctx);
+//! assert_eq!(output, b"a");
+//! ```
+
+#![allow(clippy::type_complexity)]
+
+mod drop;
+pub mod encoding;
+pub mod io;
+pub mod interpreter;
+pub mod memory;
+pub mod parser;
+pub mod preprocess;
+pub mod pretty;
+pub mod lambda;
+pub mod metacircular;
+pub mod cps;
+pub mod small_step;
+pub mod trace;
+pub mod stats;
+#[cfg(test)] mod tests;
+
+use std::rc::Rc;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+pub use crate::parser::parse_str as parse;
+pub use crate::interpreter::Interpreter;
+pub use crate::encoding::Encoding;
+use crate::io::Io;
+use crate::trace::{Event, Record, Tracer};
+
+/// Evaluation context: program input and output,
+/// the character last read by `@`, resource limits and tracing.
+pub struct Ctx<'a> {
+    io: Box<dyn Io + 'a>,
+    cur_char: Option<char>,
+    steps: u64,
+    max_steps: Option<u64>,
+    max_memory: Option<usize>,
+    cancel_token: Option<CancelToken>,
+    deadline: Option<Instant>,
+    yield_on_output: bool,
+    tracer: Option<&'a mut dyn Tracer>,
+    // reported in traces, only known to interpreters with explicit continuations
+    // and only kept up to date while tracing
+    depth: Option<usize>,
+}
+
+impl<'a> Ctx<'a> {
+    /// Characters are written in UTF-8.
+    pub fn new(output: &'a mut dyn Write, input: &'a mut dyn Iterator<Item=char>) -> Self {
+        Self::with_io(io::Chars::new(input, output))
+    }
+
+    /// Input is decoded and output encoded according to `encoding`,
+    /// see `io::Streams`.
+    pub fn with_encoding(output: &'a mut dyn Write, input: &'a mut dyn BufRead, encoding: Encoding) -> Self {
+        Self::with_io(io::Streams::new(input, output, encoding))
+    }
+
+    /// Pass `&mut io` to keep access to the device, e.g. to flush it afterwards.
+    pub fn with_io(io: impl Io + 'a) -> Self {
+        Ctx {
+            io: Box::new(io),
+            cur_char: None,
+            steps: 0,
+            max_steps: None,
+            max_memory: None,
+            cancel_token: None,
+            deadline: None,
+            yield_on_output: false,
+            tracer: None,
+            depth: None,
+        }
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<&'a mut dyn Tracer>) {
+        self.tracer = tracer;
+    }
+
+    #[inline]
+    pub(crate) fn trace(&mut self, event: Event) {
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.record(&Record { step: self.steps, depth: self.depth, event });
+        }
+    }
+
+    // `.x` and `r`
+    pub(crate) fn write_char(&mut self, c: char) -> Result<(), Halt> {
+        self.io.write_char(c).map_err(Halt::Io)?;
+        self.trace(Event::Output(c));
+        Ok(())
+    }
+
+    // Whether `@` can be applied without blocking,
+    // see `io::Queue`.
+    pub(crate) fn input_ready(&mut self) -> Result<bool, Halt> {
+        match self.io.is_eof() {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(Halt::Io(e)),
+        }
+    }
+
+    // `@`
+    pub(crate) fn read_char(&mut self) -> Result<Option<char>, Halt> {
+        let c = self.io.read_char().map_err(Halt::Io)?;
+        self.cur_char = c;
+        self.trace(Event::Input(c));
+        Ok(c)
+    }
+
+    /// Number of applications performed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Limits the total number of applications (counted by `steps()`).
+    /// Can be raised to resume evaluation after `Halt::OutOfFuel`.
+    pub fn set_max_steps(&mut self, max_steps: Option<u64>) {
+        self.max_steps = max_steps;
+    }
+
+    /// Limits `memory::usage()` (in bytes) during evaluation,
+    /// continuations of interpreters other than small-step are not counted.
+    /// Can be raised to resume evaluation after `Halt::OutOfMemory`.
+    /// Enables `memory::enable_accounting()` for this thread.
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) {
+        if max_memory.is_some() {
+            memory::enable_accounting();
+        }
+        self.max_memory = max_memory;
+    }
+
+    /// Evaluation will stop with `Halt::Cancelled`
+    /// soon after the token is cancelled.
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) {
+        self.cancel_token = token;
+    }
+
+    /// Evaluation will stop with `Halt::TimedOut` soon after the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Evaluation will stop with `Halt::Output` after printing each character.
+    /// Only supported by the small-step interpreter, others ignore it.
+    pub fn set_yield_on_output(&mut self, yield_on_output: bool) {
+        self.yield_on_output = yield_on_output;
+    }
+
+    // Interpreters call it between steps, the step limit is checked
+    // separately by out_of_fuel().
+    pub(crate) fn check_limits(&self) -> Option<fn(Suspended) -> Halt> {
+        // Checking the clock and the atomic flag on every step is too slow.
+        if self.steps.is_multiple_of(1024) {
+            if let Some(ref token) = self.cancel_token {
+                if token.is_cancelled() {
+                    return Some(Halt::Cancelled);
+                }
+            }
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    return Some(Halt::TimedOut);
+                }
+            }
+        }
+        if let Some(max_memory) = self.max_memory {
+            if memory::usage() > max_memory {
+                return Some(Halt::OutOfMemory);
+            }
+        }
+        None
+    }
+
+    // Interpreters call it right before each application, not between
+    // steps that apply nothing, so that a program needing exactly
+    // `max_steps` applications completes.
+    pub(crate) fn out_of_fuel(&self) -> bool {
+        self.max_steps.is_some_and(|max_steps| self.steps >= max_steps)
+    }
+}
+
+/// Allows cancelling evaluation from another thread, see `Ctx::set_cancel_token()`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Allows reusing the token for another evaluation.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+pub type EvalResult = Result<Rc<Term>, Halt>;
+
+/// Reason why evaluation stopped without producing a value.
+#[derive(Debug)]
+pub enum Halt {
+    /// The computation was prematurely terminated by `` `et ``.
+    Exit(Rc<Term>),
+    /// The limit set by `Ctx::set_max_steps()` was reached.
+    OutOfFuel(Suspended),
+    /// The limit set by `Ctx::set_max_memory()` was exceeded.
+    OutOfMemory(Suspended),
+    /// The token set by `Ctx::set_cancel_token()` was cancelled.
+    Cancelled(Suspended),
+    /// The deadline set by `Ctx::set_deadline()` has passed.
+    TimedOut(Suspended),
+    /// `@` was to be applied but the device had no input available
+    /// (see `io::Queue`), resume when there is some.
+    /// Only the small-step interpreter suspends, others stop with
+    /// `Halt::Io` of kind `WouldBlock`.
+    NeedsInput(Suspended),
+    /// The character was printed and `Ctx::set_yield_on_output()` is set.
+    Output(char, Suspended),
+    /// Reading or writing failed, or a character could not be
+    /// represented in the encoding.
+    Io(std::io::Error),
+}
+
+/// Interpreter state at the moment evaluation was interrupted.
+pub enum Suspended {
+    /// Interpreter kept its state on the native stack,
+    /// it was unwound and can't be resumed.
+    Unwound,
+    Cps(Box<dyn FnOnce(&mut Ctx) -> cps::ContResult>),
+    SmallStep(Box<small_step::Machine>),
+}
+
+impl Suspended {
+    /// Continues interrupted evaluation,
+    /// returns None if the state was not preserved.
+    pub fn resume(self, ctx: &mut Ctx) -> Option<EvalResult> {
+        match self {
+            Suspended::Unwound => None,
+            Suspended::Cps(step) => Some(cps::run(cps::ContResult::NextStep(step), ctx)),
+            Suspended::SmallStep(m) => Some(m.run(ctx)),
+        }
+    }
+}
+
+impl std::fmt::Debug for Suspended {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Suspended::Unwound => write!(f, "Unwound"),
+            Suspended::Cps(_) => write!(f, "Cps(..)"),
+            Suspended::SmallStep(_) => write!(f, "SmallStep(..)"),
+        }
+    }
+}
+
+pub enum Term {
+    K,
+    K1(Rc<Term>),
+    S,
+    S1(Rc<Term>),
+    S2(Rc<Term>, Rc<Term>),
+    I,
+    V,
+    D,
+    Promise(Rc<Term>),
+    Print(char),
+    Read,
+    CompareRead(char),
+    Reprint,
+    E,
+    C,
+    Apply(Rc<Term>, Rc<Term>),
+
+    // only used by CPS interpreter
+    Cont(Rc<dyn Fn(Rc<Term>, &mut Ctx) -> cps::ContResult>),
+
+    // only used by small-step interpreter
+    ReifiedCont(small_step::Cont)
+}
+use crate::Term::*;
+
+impl Term {
+    /// Allocates a term, taking it into account in `memory::usage()`.
+    pub fn new(t: Term) -> Rc<Term> {
+        memory::term_allocated();
+        Rc::new(t)
+    }
+}
+
+impl std::fmt::Debug for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// Iterative, terms can be nested deeper than the stack allows.
+impl std::fmt::Display for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        enum Piece<'t> {
+            Term(&'t Term),
+            Str(&'static str),
+        }
+        let mut stack = vec![Piece::Term(self)];
+        while let Some(piece) = stack.pop() {
+            let t = match piece {
+                Piece::Term(t) => t,
+                Piece::Str(s) => {
+                    f.write_str(s)?;
+                    continue;
+                }
+            };
+            // pieces are pushed in reverse order
+            match *t {
+                K => write!(f, "k")?,
+                K1(ref t) => {
+                    write!(f, "k1(")?;
+                    stack.extend([Piece::Str(")"), Piece::Term(t)]);
+                }
+                S => write!(f, "s")?,
+                S1(ref t) => {
+                    write!(f, "s1(")?;
+                    stack.extend([Piece::Str(")"), Piece::Term(t)]);
+                }
+                S2(ref x, ref y) => {
+                    write!(f, "s2(")?;
+                    stack.extend([Piece::Str(")"), Piece::Term(y), Piece::Str(", "), Piece::Term(x)]);
+                }
+                I => write!(f, "i")?,
+                V => write!(f, "v")?,
+                D => write!(f, "d")?,
+                Promise(ref t) => {
+                    write!(f, "promise(")?;
+                    stack.extend([Piece::Str(")"), Piece::Term(t)]);
+                }
+                Print(c) => if c == '\n' { write!(f, "r")? } else { write!(f, ".{}", c)? }
+                Read => write!(f, "@")?,
+                CompareRead(c) => write!(f, "?{}", c)?,
+                Reprint => write!(f, "|")?,
+                E => write!(f, "e")?,
+                C => write!(f, "c")?,
+                Cont(_) | ReifiedCont(_) => write!(f, "<cont>")?,
+                Apply(ref t, ref x) => {
+                    write!(f, "`")?;
+                    stack.extend([Piece::Term(x), Piece::Term(t)]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
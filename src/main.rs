@@ -1,194 +1,662 @@
-#![allow(clippy::type_complexity)]
-
 #[cfg(feature = "mimalloc")]
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-mod drop;
-mod parser;
-mod metacircular;
-mod cps;
-mod small_step;
-#[cfg(test)] mod tests;
+mod cli;
+mod repl;
 
+use std::io::{Read, Write};
 use std::rc::Rc;
-use std::io::{Write, Read};
+use unlambda::{interpreter, memory, CancelToken, Ctx, Encoding, Halt, Interpreter, Term};
+use unlambda::io::{Io, Repeat, Streams};
+use unlambda::lambda::Abstraction;
+use unlambda::parser::SpanMap;
+use unlambda::pretty::{self, PrettyPrinter};
+use unlambda::stats::{Stats, COMBINATORS};
+use unlambda::trace::{self, JsonTracer, Tee, TextTracer, Tracer};
+use cli::{Command, Group};
 
-pub struct Ctx<'a> {
-    output: &'a mut dyn Write,
-    input: &'a mut dyn Iterator<Item=char>,
-    cur_char: Option<char>,
+#[derive(Debug)]
+enum Source {
+    File(String),
+    Inline(String),
+    Stdin,
 }
 
-impl<'a> Ctx<'a> {
-    fn new(output: &'a mut dyn Write, input: &'a mut dyn Iterator<Item=char>) -> Self {
-        Ctx {
-            output,
-            input,
-            cur_char: None,
+impl Source {
+    fn name(&self) -> &str {
+        match *self {
+            Source::File(ref name) => name,
+            Source::Inline(_) => "-e",
+            Source::Stdin => "<stdin>",
         }
     }
-}
-
-// Err(t) means that the computation was prematurely terminated by `et.
-pub type EvalResult = Result<Rc<Term>, Rc<Term>>;
-
-pub enum Term {
-    K,
-    K1(Rc<Term>),
-    S,
-    S1(Rc<Term>),
-    S2(Rc<Term>, Rc<Term>),
-    I,
-    V,
-    D,
-    Promise(Rc<Term>),
-    Print(char),
-    Read,
-    CompareRead(char),
-    Reprint,
-    E,
-    C,
-    Apply(Rc<Term>, Rc<Term>),
-
-    // only used by CPS interpreter
-    Cont(Rc<dyn Fn(Rc<Term>, &mut Ctx) -> cps::ContResult>),
-
-    // only used by small-step interpreter
-    ReifiedCont(small_step::Cont)
-}
-use crate::Term::*;
-
-impl std::fmt::Debug for Term {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
-    }
-}
 
-impl ToString for Term {
-    fn to_string(&self) -> String {
+    fn read(&self) -> std::io::Result<Vec<u8>> {
         match *self {
-            K => String::from("k"),
-            K1(ref t) => format!("k1({})", t.to_string()),
-            S => String::from("s"),
-            S1(ref t) => format!("s1({})", t.to_string()),
-            S2(ref x, ref y) => format!("s1({}, {})", x.to_string(), y.to_string()),
-            I => String::from("i"),
-            V => String::from("v"),
-            D => String::from("d"),
-            Promise(ref t) => format!("promise({})", t.to_string()),
-            Print(c) => if c == '\n' { String::from("r") } else { format!(".{}", c) }
-            Read => String::from("@"),
-            CompareRead(c) => format!("?{}", c),
-            Reprint => String::from("|"),
-            E => String::from("e"),
-            C => String::from("c"),
-            Cont(_) | ReifiedCont(_) => String::from("<cont>"),
-            Apply(ref f, ref x) => format!("`{}{}", f.to_string(), x.to_string()),
+            Source::File(ref name) => std::fs::read(name),
+            Source::Inline(ref text) => Ok(text.clone().into_bytes()),
+            Source::Stdin => {
+                let mut bytes = Vec::new();
+                std::io::stdin().read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
         }
     }
 }
 
-#[derive(Debug)]
-enum Interpreter {
-    MetaCircular,
-    Cps,
-    SmallStep
-}
-
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct Opts {
-    file_name: String,
-    interpreter: Interpreter,
+    // applied to each other left to right, like ``abc
+    sources: Vec<Source>,
+    input: Option<String>,
+    input_string: Option<String>,
+    repeat_input: bool,
+    // None means the default one, or all of them for bench
+    interpreter: Option<&'static dyn Interpreter>,
+    encoding: Encoding,
     time: bool,
+    stats: bool,
+    expand: bool,
+    lambda: Option<Abstraction>,
+    print_result: bool,
+    result_status: bool,
+    max_steps: Option<u64>,
+    max_memory: Option<usize>,
+    timeout: Option<std::time::Duration>,
+    trace: bool,
+    trace_file: Option<String>,
+    trace_width: Option<usize>,
+    trace_filter: Option<Vec<String>>,
+    trace_json: bool,
+    width: usize,
+    strip_comments: bool,
+    runs: usize,
 }
 
 impl Opts {
-    fn parse(mut pargs: pico_args::Arguments) -> Result<Opts, pico_args::Error> {
-        let interpreter: Interpreter = pargs.opt_value_from_fn("--interpreter", |s| {
-            match s.to_ascii_lowercase().as_str() {
-                "metacircular" => Ok(Interpreter::MetaCircular),
-                "cps" => Ok(Interpreter::Cps),
-                "smallstep" => Ok(Interpreter::SmallStep),
-                _ => Err("unrecognized interpreter"),
+    // Options the command doesn't have are left over and reported as unknown.
+    fn parse(command: Command, mut pargs: pico_args::Arguments) -> Result<Opts, pico_args::Error> {
+        let invalid = |value: &str, cause: &str| pico_args::Error::Utf8ArgumentParsingFailed {
+            value: value.to_string(),
+            cause: cause.to_string(),
+        };
+        let mut opts = Opts { runs: 5, ..Default::default() };
+        let mut inline = Vec::new();
+        if command.has(Group::Source) {
+            inline = pargs.values_from_str("-e")?;
+            let lambda = pargs.contains("--lambda");
+            let abstraction = pargs.opt_value_from_fn("--abstraction", |s| match s {
+                "naive" => Ok(Abstraction::Naive),
+                "turner" => Ok(Abstraction::Turner),
+                _ => Err("unrecognized abstraction algorithm"),
+            })?;
+            opts.lambda = match (lambda, abstraction) {
+                (false, None) => None,
+                (_, a) => Some(a.unwrap_or(Abstraction::Turner)),
+            };
+        }
+        if command.has(Group::Encoding) {
+            opts.encoding = pargs.opt_value_from_fn("--encoding", |s| {
+                Encoding::find(s).ok_or("unrecognized encoding")
+            })?.unwrap_or_default();
+        }
+        if command.has(Group::Interpreter) {
+            opts.interpreter = pargs.opt_value_from_fn("--interpreter", |s| {
+                interpreter::find(s).ok_or("unrecognized interpreter")
+            })?;
+        }
+        if command.has(Group::Limits) {
+            opts.max_steps = pargs.opt_value_from_str("--max-steps")?;
+            opts.max_memory = pargs.opt_value_from_fn("--max-memory", parse_size)?;
+            opts.timeout = pargs.opt_value_from_fn("--timeout", |s| {
+                let secs: f64 = s.parse().map_err(|e: std::num::ParseFloatError| e.to_string())?;
+                std::time::Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+            })?;
+        }
+        if command.has(Group::Input) {
+            opts.input = pargs.opt_value_from_str("--input")?;
+            opts.input_string = pargs.opt_value_from_str("--input-string")?;
+            if opts.input.is_some() && opts.input_string.is_some() {
+                return Err(invalid("--input-string", "can't be combined with --input"));
+            }
+            opts.repeat_input = pargs.opt_value_from_fn("--eof", |s| match s {
+                "hard" => Ok(false),
+                "repeat" => Ok(true),
+                _ => Err("expected hard or repeat"),
+            })?.unwrap_or(false);
+        }
+        if command.has(Group::Report) {
+            opts.time = pargs.contains("--time");
+            opts.stats = pargs.contains("--stats");
+            opts.print_result = pargs.contains("--print-result");
+            opts.result_status = pargs.contains("--result-status");
+        }
+        if command.has(Group::Trace) {
+            opts.trace = pargs.contains("--trace") || command == Command::Trace;
+            opts.trace_file = pargs.opt_value_from_str("--trace-file")?;
+            opts.trace_width = pargs.opt_value_from_str("--trace-width")?;
+            opts.trace_filter = pargs.opt_value_from_fn("--trace-filter", |s| {
+                Ok::<_, String>(s.split(',').map(String::from).collect())
+            })?;
+            opts.trace_json = pargs.opt_value_from_fn("--trace-format", |s| match s {
+                "text" => Ok(false),
+                "json" => Ok(true),
+                _ => Err("expected text or json"),
+            })?.unwrap_or(false);
+        }
+        if command.has(Group::Expand) {
+            opts.expand = pargs.contains("--expand");
+        }
+        if command.has(Group::Format) {
+            opts.width = pargs.opt_value_from_str("--width")?.unwrap_or(pretty::DEFAULT_WIDTH);
+            opts.strip_comments = pargs.contains("--strip-comments");
+        }
+        if command.has(Group::Bench) {
+            opts.runs = pargs.opt_value_from_str("--runs")?.unwrap_or(opts.runs);
+            if opts.runs == 0 {
+                return Err(invalid("--runs", "at least one run is needed"));
             }
-        })?.unwrap_or(Interpreter::SmallStep);
-        let time: bool = pargs.contains("--time");
-        let file_name: String = pargs.free_from_str()?;
+        }
 
-        let remaining = pargs.finish();
-        if !remaining.is_empty() {
-            eprintln!("warning: unused arguments {:?}", remaining);
+        opts.sources = inline.into_iter().map(Source::Inline).collect();
+        for arg in pargs.finish() {
+            let arg = arg.into_string().map_err(|_| pico_args::Error::NonUtf8Argument)?;
+            let source = match arg.as_str() {
+                a if a.starts_with('-') && a != "-" => return Err(invalid(a, "unknown option")),
+                _ if !command.takes_program() => return Err(invalid(&arg, "unexpected argument")),
+                "-" if opts.sources.iter().any(|s| matches!(s, Source::Stdin)) => {
+                    return Err(invalid(&arg, "stdin can only be read once"));
+                }
+                "-" => Source::Stdin,
+                _ => Source::File(arg),
+            };
+            if matches!(opts.sources.first(), Some(Source::Inline(_))) {
+                return Err(invalid("-e", "can't be combined with files"));
+            }
+            opts.sources.push(source);
+        }
+        if opts.sources.is_empty() && command.takes_program() {
+            return Err(pico_args::Error::MissingArgument);
         }
-        Ok(Opts {
-            file_name,
-            interpreter,
-            time,
-        })
+        Ok(opts)
     }
-}
-
-const USAGE: &str =
-"USAGE:
-    unlambda.exe [--time] [--interpreter=...] <file-name>
 
-    --time
-        Print execution time to stderr
+    fn interpreter(&self) -> &'static dyn Interpreter {
+        self.interpreter.unwrap_or_else(interpreter::default)
+    }
+}
 
-    --interpreter <interpreter>
-        Possible values: MetaCircular, CPS, SmallStep (default)
-";
+// "123", "64K", "10M", "1G"
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 1 << 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 1 << 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let n: usize = digits.parse().map_err(|e: std::num::ParseIntError| e.to_string())?;
+    n.checked_mul(multiplier).ok_or_else(|| String::from("too large"))
+}
 
 fn main() {
-    let mut pargs = pico_args::Arguments::from_env();
+    let mut args: Vec<_> = std::env::args_os().skip(1).collect();
+    if args.first().is_some_and(|a| a == "help") {
+        match args.get(1).and_then(|a| a.to_str()) {
+            None => print!("{}", cli::overview()),
+            Some(name) => match Command::find(name) {
+                Some(command) => print!("{}", cli::help(command)),
+                None => {
+                    eprintln!("error: unknown command {}", name);
+                    std::process::exit(1);
+                }
+            },
+        }
+        return;
+    }
+    // without a command, the arguments are those of run
+    let explicit = args.first().and_then(|a| a.to_str()).and_then(Command::find);
+    if explicit.is_some() {
+        args.remove(0);
+    }
+    let command = explicit.unwrap_or(Command::Run);
+
+    let mut pargs = pico_args::Arguments::from_vec(args);
     if pargs.contains(["-h", "--help"]) {
-        eprintln!("{}", USAGE);
-        std::process::exit(0);
+        match explicit {
+            Some(command) => print!("{}", cli::help(command)),
+            None => print!("{}", cli::overview()),
+        }
+        return;
     }
-    let opts = match Opts::parse(pargs) {
+    let opts = match Opts::parse(command, pargs) {
         Ok(opts) => opts,
         Err(e) => {
-            eprintln!("{}", USAGE);
-            eprintln!("{}", e);
+            eprintln!("error: {}", e);
+            eprintln!("See `unlambda {} --help`.", command.name());
             std::process::exit(1);
         }
     };
 
-    let program = std::fs::read_to_string(&opts.file_name).unwrap();
+    if opts.stats || opts.max_memory.is_some() {
+        // so that terms of the program are counted too
+        memory::enable_accounting();
+    }
+
+    match command {
+        Command::Run if opts.expand => compile(&opts),
+        Command::Run | Command::Trace => run(opts),
+        Command::Check => check(&opts),
+        Command::Fmt => fmt(&opts),
+        Command::Bench => bench(&opts),
+        Command::Compile => compile(&opts),
+        Command::Minify => minify(&opts),
+        Command::Repl => {
+            let interrupt = CancelToken::new();
+            let token = interrupt.clone();
+            // the first Ctrl-C stops the evaluation, the second one the session
+            let handler = ctrlc::set_handler(move || {
+                if token.is_cancelled() {
+                    std::process::exit(130);
+                }
+                token.cancel();
+            });
+            if let Err(e) = handler {
+                eprintln!("warning: Ctrl-C will end the session: {}", e);
+            }
+            let stdin = std::io::stdin();
+            let r = repl::run(&mut stdin.lock(), &mut std::io::stdout(), opts.interpreter(), opts.encoding, &interrupt);
+            if let Err(e) = r {
+                io_error(&e);
+            }
+        }
+    }
+}
+
+fn compile(opts: &Opts) {
+    // separated by newlines, so that trailing comments end
+    let mut text = "`".repeat(opts.sources.len() - 1);
+    for (i, source) in opts.sources.iter().enumerate() {
+        if i > 0 && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(&expand(&read_source(source, opts), opts));
+    }
+    print!("{}", text);
+}
 
-    let mut stdout = std::io::stdout();
-    let stdin = std::io::stdin();
-    let mut it = stdin.lock().bytes().map(|b| {
-        let b = b.unwrap();
-        assert!(b < 128);
-        b as char
+fn fmt(opts: &Opts) {
+    let printer = PrettyPrinter::new()
+        .with_width(opts.width)
+        .with_comments(!opts.strip_comments);
+    let (loaded, program) = load_all(opts);
+    // the output replaces the source, which must not lose its definitions
+    let compiled = opts.lambda.is_some() || loaded.iter().any(|l| {
+        // already checked by load()
+        unlambda::preprocess::expand(&l.source.text).unwrap().text != l.source.text
     });
-    let mut ctx = Ctx::new(&mut stdout, &mut it);
+    if compiled {
+        eprintln!("error: fmt doesn't support `let` definitions or --lambda, see the compile command");
+        std::process::exit(1);
+    }
+    match loaded[..] {
+        // comments can only be placed in a single source
+        [ref l] => print!("{}", printer.format_source(&l.source.text).unwrap()),
+        _ => print!("{}", printer.format(&program)),
+    }
+}
+
+fn minify(opts: &Opts) {
+    let (_, program) = load_all(opts);
+    match pretty::minify(&program) {
+        Some(text) => print!("{}", text),
+        None => {
+            eprintln!("error: the minified program does not parse back to the same one");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn check(opts: &Opts) {
+    let (_, program) = load_all(opts);
+    let mut used = [false; COMBINATORS.len()];
+    let mut applications = 0;
+    let mut stack = vec![&*program];
+    while let Some(t) = stack.pop() {
+        if let Term::Apply(ref f, ref x) = *t {
+            applications += 1;
+            stack.push(x);
+            stack.push(f);
+        } else if let Some(i) = COMBINATORS.iter().position(|&c| c == trace::combinator(t)) {
+            used[i] = true;
+        }
+    }
+    let used: Vec<&str> = COMBINATORS.iter().zip(used).filter(|p| p.1).map(|p| *p.0).collect();
+    let supported: Vec<&str> = interpreter::all().iter()
+        .filter(|i| i.check(&program).is_ok())
+        .map(|i| i.name())
+        .collect();
+    println!("applications: {}", applications);
+    println!("uses: {}", used.join(" "));
+    println!("interpreters: {}", supported.join(", "));
+}
 
-    let program = parser::parse_str(&program);
-    match program {
-        Ok(program) => {
+fn bench(opts: &Opts) {
+    let (loaded, program) = load_all(opts);
+    let interpreters: Vec<&dyn Interpreter> = match opts.interpreter {
+        Some(interp) => {
+            check_support(interp, &program, &loaded);
+            vec![interp]
+        }
+        None => interpreter::all().iter().copied().filter(|i| i.check(&program).is_ok()).collect(),
+    };
+    // every run gets the same input
+    let mut input = Vec::new();
+    if let Err(e) = open_input(opts).read_to_end(&mut input) {
+        io_error(&e);
+    }
+    for interp in interpreters {
+        let mut times = Vec::new();
+        let mut steps = 0;
+        for _ in 0..opts.runs {
+            let streams = Streams::new(&input[..], std::io::sink(), opts.encoding);
+            let mut io: Box<dyn Io> = if opts.repeat_input {
+                Box::new(Repeat::new(streams))
+            } else {
+                Box::new(streams)
+            };
+            let mut ctx = Ctx::with_io(&mut *io);
+            ctx.set_max_steps(opts.max_steps);
+            ctx.set_max_memory(opts.max_memory);
             let start = std::time::Instant::now();
-            {
-                let _ = match opts.interpreter {
-                    Interpreter::MetaCircular => {
-                        if metacircular::contains_c(&program) {
-                            eprintln!("Metacircular interpreter does not support call/cc");
-                            std::process::exit(1);
-                        }
-                        metacircular::eval(program, &mut ctx)
-                    }
-                    Interpreter::Cps => cps::full_eval(program, &mut ctx),
-                    Interpreter::SmallStep => small_step::full_eval(program, &mut ctx),
-                };
+            // None if too far in the future to be reached
+    ctx.set_deadline(opts.timeout.and_then(|t| start.checked_add(t)));
+            let result = interp.eval(Rc::clone(&program), &mut ctx);
+            times.push(start.elapsed().as_secs_f64());
+            steps = ctx.steps();
+            match result {
+                Ok(_) | Err(Halt::Exit(_)) => {}
+                Err(h) => stopped(h),
             }
-            if opts.time {
-                eprintln!("It took {}s", start.elapsed().as_secs_f64());
+        }
+        times.sort_by(f64::total_cmp);
+        println!("{:<14}{:>12} steps   min {:.3}s   median {:.3}s",
+                 interp.name(), steps, times[0], times[times.len() / 2]);
+    }
+}
+
+fn run(opts: Opts) {
+    let (loaded, program) = load_all(&opts);
+    check_support(opts.interpreter(), &program, &loaded);
+
+    let streams = Streams::new(open_input(&opts), std::io::stdout(), opts.encoding);
+    let mut io: Box<dyn Io> = if opts.repeat_input {
+        Box::new(Repeat::new(streams))
+    } else {
+        Box::new(streams)
+    };
+
+    // cancelled when the trace can't be written, e.g. piped to `head`
+    let trace_failed = CancelToken::new();
+    let mut tracer = if opts.trace || opts.trace_file.is_some() {
+        let out: Box<dyn std::io::Write> = match opts.trace_file {
+            Some(ref f) => match std::fs::File::create(f) {
+                Ok(file) => Box::new(std::io::BufWriter::new(file)),
+                Err(e) => {
+                    eprintln!("error: {}: {}", f, e);
+                    std::process::exit(7);
+                }
+            },
+            None => Box::new(std::io::stderr()),
+        };
+        let out = Box::new(Cancelling(out, trace_failed.clone()));
+        let tracer: Box<dyn Tracer> = if opts.trace_json {
+            let mut tracer = JsonTracer::new(out);
+            if let Some(width) = opts.trace_width {
+                tracer = tracer.with_width(width);
+            }
+            if let Some(ref filter) = opts.trace_filter {
+                tracer = tracer.with_filter(filter.clone());
+            }
+            Box::new(tracer)
+        } else {
+            let width = opts.trace_width.unwrap_or(60);
+            let mut tracer = TextTracer::new(out, width);
+            // positions would be ambiguous with several sources
+            if let [ref l] = loaded[..] {
+                tracer = tracer.with_spans(&l.spans, &l.source.text);
             }
+            if let Some(ref filter) = opts.trace_filter {
+                tracer = tracer.with_filter(filter.clone());
+            }
+            Box::new(tracer)
+        };
+        Some(tracer)
+    } else {
+        None
+    };
+
+    let mut stats = if opts.stats { Some(Stats::new()) } else { None };
+    let mut tracers: Vec<&mut dyn Tracer> = Vec::new();
+    if let Some(ref mut tracer) = tracer {
+        tracers.push(&mut **tracer);
+    }
+    if let Some(ref mut stats) = stats {
+        tracers.push(stats);
+    }
+    let mut tee = Tee(tracers);
+
+    let mut ctx = Ctx::with_io(&mut *io);
+    ctx.set_max_steps(opts.max_steps);
+    ctx.set_max_memory(opts.max_memory);
+    if !tee.0.is_empty() {
+        // tracing slows evaluation down, so only when needed
+        ctx.set_tracer(Some(&mut tee));
+        // tracers stop at write errors, so does evaluation
+        ctx.set_cancel_token(Some(trace_failed));
+    }
+
+    let start = std::time::Instant::now();
+    // None if too far in the future to be reached
+    ctx.set_deadline(opts.timeout.and_then(|t| start.checked_add(t)));
+    // keeps source terms alive so that spans remain valid
+    let result = opts.interpreter().eval(Rc::clone(&program), &mut ctx);
+    drop(ctx);
+    if let Err(e) = tee.finish() {
+        let _ = io.flush();
+        io_error(&e);
+    }
+    if opts.time {
+        eprintln!("It took {}s", start.elapsed().as_secs_f64());
+    }
+    if let Some(ref stats) = stats {
+        if let Err(e) = stats.report(&mut std::io::stderr()) {
+            io_error(&e);
+        }
+    }
+    let (value, exited) = match result {
+        Ok(t) => (t, false),
+        Err(Halt::Exit(t)) => (t, true),
+        Err(h) => {
+            let _ = io.flush();
+            stopped(h)
+        }
+    };
+    // stdout is line buffered, this reports failures to write the last line
+    // and keeps the order of program output and the result on a terminal
+    if let Err(e) = io.flush() {
+        io_error(&e);
+    }
+    if opts.print_result {
+        // as source if possible, so that it can be run again
+        let text = pretty::to_source_or_display(&value);
+        if exited {
+            eprintln!("exited with {}", text);
+        } else {
+            eprintln!("result: {}", text);
         }
+    }
+    if opts.result_status {
+        std::process::exit(result_status(&value));
+    }
+}
+
+struct Cancelling<W>(W, CancelToken);
+
+impl<W: std::io::Write> std::io::Write for Cancelling<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf).inspect_err(|_| self.1.cancel())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush().inspect_err(|_| self.1.cancel())
+    }
+}
+
+struct Text {
+    text: String,
+    // for messages, names the source if there are several
+    prefix: String,
+}
+
+struct Loaded {
+    source: Text,
+    program: Rc<Term>,
+    spans: SpanMap,
+}
+
+// Functions below exit on errors.
+
+fn read_source(source: &Source, opts: &Opts) -> Text {
+    let bytes = match source.read() {
+        Ok(bytes) => bytes,
         Err(e) => {
-            eprintln!("Parse error: {}", e);
+            eprintln!("error: {}: {}", source.name(), e);
+            std::process::exit(7);
+        }
+    };
+    let text = match opts.encoding.decode(bytes) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("error: {}: {}", source.name(), e);
             std::process::exit(2);
         }
+    };
+    let prefix = if opts.sources.len() > 1 { format!("{}: ", source.name()) } else { String::new() };
+    Text { text, prefix }
+}
+
+fn syntax_error(l: &Text, rendered: String) -> ! {
+    eprint!("{}{}", l.prefix, rendered);
+    std::process::exit(2);
+}
+
+// Plain Unlambda source of the program, as printed by --expand.
+fn expand(l: &Text, opts: &Opts) -> String {
+    match opts.lambda {
+        Some(abstraction) => match unlambda::lambda::compile(&l.text, abstraction) {
+            Ok(p) => format!("{}\n", p),
+            Err(e) => syntax_error(l, e.render(&l.text)),
+        },
+        None => match unlambda::preprocess::expand(&l.text) {
+            Ok(e) => e.text,
+            Err(e) => syntax_error(l, e.render(&l.text)),
+        },
+    }
+}
+
+fn load(source: &Source, opts: &Opts) -> Loaded {
+    let l = read_source(source, opts);
+    let (program, spans) = if let Some(abstraction) = opts.lambda {
+        match unlambda::lambda::compile(&l.text, abstraction) {
+            Ok(p) => (p, SpanMap::default()),
+            Err(e) => syntax_error(&l, e.render(&l.text)),
+        }
+    } else {
+        let expansion = match unlambda::preprocess::expand(&l.text) {
+            Ok(e) => e,
+            Err(e) => syntax_error(&l, e.render(&l.text)),
+        };
+        match expansion.parse_with_spans(&l.text) {
+            Ok(p) => p,
+            Err(e) => syntax_error(&l, e.render(&l.text)),
+        }
+    };
+    Loaded { source: l, program, spans }
+}
+
+fn load_all(opts: &Opts) -> (Vec<Loaded>, Rc<Term>) {
+    let loaded: Vec<Loaded> = opts.sources.iter().map(|s| load(s, opts)).collect();
+    let program = loaded.iter()
+        .map(|l| Rc::clone(&l.program))
+        .reduce(|f, x| Term::new(Term::Apply(f, x)))
+        .unwrap();
+    (loaded, program)
+}
+
+fn check_support(interp: &dyn Interpreter, program: &Term, loaded: &[Loaded]) {
+    if let Err(e) = interp.check(program) {
+        eprintln!("{}", e);
+        let c = unlambda::metacircular::find_c(program);
+        let span = c.and_then(|c| loaded.iter().find_map(|l| Some((l, l.spans.get(c)?))));
+        if let Some((l, span)) = span {
+            eprint!("{}{}", l.source.prefix, span.render(&l.source.text, "call/cc is used here"));
+        }
+        std::process::exit(1);
+    }
+}
+
+fn open_input(opts: &Opts) -> Box<dyn std::io::BufRead + '_> {
+    match (&opts.input, &opts.input_string) {
+        (Some(f), _) => match std::fs::File::open(f) {
+            Ok(file) => Box::new(std::io::BufReader::new(file)),
+            Err(e) => {
+                eprintln!("error: {}: {}", f, e);
+                std::process::exit(7);
+            }
+        },
+        (None, Some(s)) => Box::new(s.as_bytes()),
+        (None, None) => Box::new(std::io::stdin().lock()),
+    }
+}
+
+// Evaluation didn't produce a value.
+fn stopped(h: Halt) -> ! {
+    match h {
+        Halt::OutOfFuel(_) => {
+            eprintln!("Step limit exceeded");
+            std::process::exit(3);
+        }
+        Halt::OutOfMemory(_) => {
+            eprintln!("Memory limit exceeded");
+            std::process::exit(4);
+        }
+        Halt::TimedOut(_) => {
+            eprintln!("Time limit exceeded");
+            std::process::exit(5);
+        }
+        Halt::Io(e) => io_error(&e),
+        Halt::Exit(_) => unreachable!("not an error"),
+        // stdin blocks and the options are not set
+        Halt::Cancelled(_) | Halt::NeedsInput(_) | Halt::Output(..) => unreachable!(),
+    }
+}
+
+// Output going to `head` or similar that exits early is not an error.
+fn io_error(e: &std::io::Error) -> ! {
+    if e.kind() == std::io::ErrorKind::BrokenPipe {
+        std::process::exit(0);
+    }
+    // stderr may be what failed, eprintln!() would panic
+    let _ = writeln!(std::io::stderr(), "error: {}", e);
+    std::process::exit(7);
+}
+
+fn result_status(t: &Term) -> i32 {
+    match *t {
+        Term::I => 0,
+        // 1 is taken by usage errors
+        Term::V => 8,
+        _ => 6,
     }
 }
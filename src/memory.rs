@@ -0,0 +1,96 @@
+// Rough accounting of memory taken by evaluation,
+// used to enforce Ctx::set_max_memory() and reported by Stats.
+//
+// Only terms allocated with Term::new() and continuation entries
+// of the small-step interpreter are tracked. CPS closures
+// and the native stack of the metacircular interpreter are not.
+//
+// Accounting slows evaluation down, so it's off until something
+// needs it. Terms allocated before that are not counted.
+// Term is a public enum, so terms can also be allocated with Rc::new()
+// or live on the stack. Every dropped term is uncounted,
+// so these make the count lower than it should be.
+
+use std::cell::Cell;
+use crate::Term;
+use crate::small_step::ContEntry;
+
+#[derive(Clone, Copy)]
+struct Counts {
+    live: usize,
+    allocated: u64,
+}
+
+thread_local! {
+    // None until accounting is enabled
+    static TERMS: Cell<Option<Counts>> = const { Cell::new(None) };
+}
+
+/// Starts counting terms and continuation entries in this thread.
+/// Called by `Ctx::set_max_memory()` and `Stats::new()`,
+/// call it before parsing to take the program into account too.
+pub fn enable_accounting() {
+    TERMS.with(|c| if c.get().is_none() {
+        c.set(Some(Counts { live: 0, allocated: 0 }));
+    });
+    rc_stack::enable_counting();
+}
+
+pub(crate) fn term_allocated() {
+    TERMS.with(|c| if let Some(n) = c.get() {
+        c.set(Some(Counts { live: n.live + 1, allocated: n.allocated + 1 }));
+    });
+}
+
+// Called for every term dropped, counted or not.
+pub(crate) fn term_dropped() {
+    TERMS.with(|c| if let Some(n) = c.get() {
+        c.set(Some(Counts { live: n.live.saturating_sub(1), ..n }));
+    });
+}
+
+/// Number of terms allocated by `Term::new()` in this thread
+/// that are still alive, 0 if accounting is not enabled.
+pub fn live_terms() -> usize {
+    TERMS.with(|c| c.get().map_or(0, |n| n.live))
+}
+
+/// Total number of terms allocated by `Term::new()` in this thread
+/// since accounting was enabled.
+pub fn allocated_terms() -> u64 {
+    TERMS.with(|c| c.get().map_or(0, |n| n.allocated))
+}
+
+/// Approximate number of bytes taken by live terms
+/// and continuation entries of the small-step interpreter in this thread.
+pub fn usage() -> usize {
+    // Rc adds strong and weak counts, RcStack adds a ref count.
+    let term_size = std::mem::size_of::<Term>() + 2 * std::mem::size_of::<usize>();
+    let entry_size = std::mem::size_of::<(ContEntry, usize)>();
+    live_terms() * term_size + rc_stack::live_items() * entry_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use crate::Term::*;
+
+    #[test]
+    fn counting() {
+        enable_accounting();
+        let before = live_terms();
+        let allocated_before = allocated_terms();
+        let t = Term::new(Apply(Term::new(K), Term::new(I)));
+        assert_eq!(live_terms(), before + 3);
+        drop(t);
+        assert_eq!(live_terms(), before);
+        assert_eq!(allocated_terms(), allocated_before + 3);
+
+        // terms not allocated by Term::new() are not counted
+        let t = Rc::new(K1(Term::new(K)));
+        assert_eq!(live_terms(), before + 1);
+        assert_eq!(allocated_terms(), allocated_before + 4);
+        drop(t);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::Ctx;
 use crate::EvalResult;
+use crate::{Halt, Suspended};
+use crate::Interpreter;
+use crate::trace::Event;
 use crate::Term;
 use crate::Term::*;
 use std::rc::Rc;
@@ -10,7 +13,7 @@ pub fn eval(term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
     if let Apply(ref f, ref x) = *term {
         let ef = eval(Rc::clone(f), ctx)?;
         if let D = *ef {
-            return Ok(Rc::new(Promise(Rc::clone(x))));
+            return Ok(delay(x, ctx));
         }
         return apply(
             ef,
@@ -19,6 +22,27 @@ pub fn eval(term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
     Ok(term)
 }
 
+// Kept out of eval() and apply() to make their stack frames smaller,
+// they recurse as deep as the program is nested.
+#[inline(never)]
+fn delay(x: &Rc<Term>, ctx: &mut Ctx) -> Rc<Term> {
+    ctx.trace(Event::Promise { x });
+    Term::new(Promise(Rc::clone(x)))
+}
+
+#[inline(never)]
+fn begin_step(f: &Rc<Term>, x: &Rc<Term>, ctx: &mut Ctx) -> Result<(), Halt> {
+    if let Some(halt) = ctx.check_limits() {
+        return Err(halt(Suspended::Unwound));
+    }
+    if ctx.out_of_fuel() {
+        return Err(Halt::OutOfFuel(Suspended::Unwound));
+    }
+    ctx.steps += 1;
+    ctx.trace(Event::Apply { f, x });
+    Ok(())
+}
+
 // Never returns Apply(...) term.
 fn apply(f: Rc<Term>, x: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
     if let Apply(_, _) = *f {
@@ -27,11 +51,14 @@ fn apply(f: Rc<Term>, x: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
     if let Apply(_, _) = *x {
         panic!();
     }
-    Ok(match *f {
-        K => Rc::new(K1(x)),
-        K1(ref y) => Rc::clone(y),
-        S => Rc::new(S1(x)),
-        S1(ref y) => Rc::new(S2(Rc::clone(y), x)),
+    begin_step(&f, &x, ctx)?;
+    // Applications to evaluate share a single call to eval() at the end,
+    // every call site would add its temporaries to the stack frame.
+    let t = match *f {
+        K => return Ok(Term::new(K1(x))),
+        K1(ref y) => return Ok(Rc::clone(y)),
+        S => return Ok(Term::new(S1(x))),
+        S1(ref y) => return Ok(Term::new(S2(Rc::clone(y), x))),
 
         // Seems a bit redundant, since x, y, and z are already evaluated.
         // But we can't just write "apply(apply(y, x), apply(z, x))"
@@ -39,65 +66,82 @@ fn apply(f: Rc<Term>, x: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
         // See example ```s`kdri in the documentation.
         // eval() is idempotent, so repeated evaluation of x, y, z is fine.
         S2(ref y, ref z) =>
-            eval(Rc::new(Apply(
-                Rc::new(Apply(Rc::clone(y), Rc::clone(&x))),
-                Rc::new(Apply(Rc::clone(z), Rc::clone(&x))))), ctx)?,
+            Term::new(Apply(
+                Term::new(Apply(Rc::clone(y), Rc::clone(&x))),
+                Term::new(Apply(Rc::clone(z), Rc::clone(&x))))),
 
         Print(c) => {
-            ctx.output.write_fmt(format_args!("{}", c)).unwrap();
-            x
+            ctx.write_char(c)?;
+            return Ok(x);
         }
-        I => x,
-        V => f,
-        E => return Err(x),
+        I => return Ok(x),
+        V => return Ok(f),
+        E => return Err(Halt::Exit(x)),
         Read => {
-            let c = ctx.input.next();
-            ctx.cur_char = c;
-            let t = match c {
-                Some(_) => Rc::new(I),
-                None => Rc::new(V),
+            let t = match ctx.read_char()? {
+                Some(_) => Term::new(I),
+                None => Term::new(V),
             };
-            eval(Rc::new(Apply(x, t)), ctx)?
+            Term::new(Apply(x, t))
         }
         CompareRead(c) => {
             let t = match ctx.cur_char {
-                Some(cc) if cc == c => Rc::new(I),
-                _ => Rc::new(V),
+                Some(cc) if cc == c => Term::new(I),
+                _ => Term::new(V),
             };
-            eval(Rc::new(Apply(x, t)), ctx)?
+            Term::new(Apply(x, t))
         }
         Reprint => {
             let t = match ctx.cur_char {
-                Some(c) => Rc::new(Print(c)),
-                None => Rc::new(V),
+                Some(c) => Term::new(Print(c)),
+                None => Term::new(V),
             };
-            eval(Rc::new(Apply(x, t)), ctx)?
+            Term::new(Apply(x, t))
         }
         D => panic!("should be handled in eval"),
 
         // Similarly, apply(eval(f), x) instead of eval(`fx)
         // is probably incorrect. What if f = Promise(D)?
-        Promise(ref f) => eval(Rc::new(Apply(Rc::clone(f), x)), ctx)?,
+        Promise(ref f) => Term::new(Apply(Rc::clone(f), x)),
 
         C => panic!("unsupported"),
         Cont(_) => panic!("unsupported"),
         ReifiedCont(_) => panic!("unsupported"),
 
         Apply(_, _) => panic!("should be handled by eval()")
-    })
+    };
+    eval(t, ctx)
+}
+
+pub struct MetaCircular;
+
+impl Interpreter for MetaCircular {
+    fn name(&self) -> &'static str { "metacircular" }
+    fn supports_call_cc(&self) -> bool { false }
+    fn supports_stepping(&self) -> bool { false }
+    fn supports_resuming(&self) -> bool { false }
+
+    fn run(&self, term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
+        eval(term, ctx)
+    }
 }
 
 pub fn contains_c(t: &Term) -> bool {
+    find_c(t).is_some()
+}
+
+/// Leftmost occurrence of `c` in the program.
+pub fn find_c(t: &Term) -> Option<&Term> {
     let mut q = vec![t];
     while let Some(t) = q.pop() {
         match *t {
-            C => return true,
+            C => return Some(t),
             Apply(ref f, ref x) => {
-                q.push(f);
                 q.push(x);
+                q.push(f);
             }
             _ => {}
         }
     }
-    false
+    None
 }
@@ -1,37 +1,231 @@
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::str::CharIndices;
 use crate::Term;
 use crate::Term::*;
 
-pub fn parse_str(s: &str) -> Result<Rc<Term>, String> {
-    let mut path: Vec<Option<Rc<Term>>> = Vec::new();
-    let mut it = s.chars();
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnexpectedEof,
+    /// EOF right after '.' or '?'.
+    UnexpectedEofAfter(char),
+    Unrecognized(char),
+    /// Something other than whitespace or comment after a complete program.
+    Unexpected(char),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Byte offset of the offending character (or source length on EOF).
+    pub offset: usize,
+    /// 1-based.
+    pub line: usize,
+    /// 1-based, in characters.
+    pub column: usize,
+    /// Byte offsets of backticks that are still waiting
+    /// for their arguments, outermost first.
+    pub unmatched: Vec<usize>,
+}
+
+impl std::fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ParseErrorKind::UnexpectedEof => write!(f, "unexpected EOF"),
+            ParseErrorKind::UnexpectedEofAfter(c) => write!(f, "unexpected EOF after {:?}", c),
+            ParseErrorKind::Unrecognized(c) => write!(f, "unrecognized {:?}", c),
+            ParseErrorKind::Unexpected(c) => write!(f, "unexpected {:?}", c),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, s: &str, offset: usize, path: &[(usize, Option<Rc<Term>>)]) -> Self {
+        let (line, column) = line_col(s, offset);
+        ParseError {
+            kind,
+            offset,
+            line,
+            column,
+            unmatched: path.iter().map(|p| p.0).collect(),
+        }
+    }
+
+    /// Human-readable message with the relevant source lines
+    /// and the error position underlined.
+    pub fn render(&self, s: &str) -> String {
+        let mut result = format!("error: {}\n", self.kind);
+        result.push_str(&excerpt(s, self.offset, "here"));
+        if let Some(&innermost) = self.unmatched.last() {
+            let (line, column) = line_col(s, innermost);
+            result.push_str(&format!(
+                "note: {} unmatched '`', innermost at {}:{}\n",
+                self.unmatched.len(), line, column));
+            result.push_str(&excerpt(s, innermost, "this application is incomplete"));
+        }
+        result
+    }
+}
+
+/// Byte range in the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Source line with the start of the span underlined.
+    pub fn render(&self, s: &str, label: &str) -> String {
+        let (line, column) = line_col(s, self.start);
+        format!("at {}:{}\n{}", line, column, excerpt(s, self.start, label))
+    }
+}
+
+/// Source locations of parsed terms, keyed by node identity.
+///
+/// Only meaningful while the parsed tree is alive (keep a clone of the root),
+/// otherwise the addresses could be reused by unrelated terms.
+#[derive(Debug, Default)]
+pub struct SpanMap(HashMap<*const Term, Span>);
+
+impl SpanMap {
+    pub fn get(&self, t: &Term) -> Option<Span> {
+        self.0.get(&(t as *const Term)).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn map_offsets(&mut self, f: impl Fn(usize) -> usize) {
+        for span in self.0.values_mut() {
+            // spans are never empty
+            *span = Span { start: f(span.start), end: f(span.end - 1) + 1 };
+        }
+    }
+
+    fn insert(&mut self, t: &Rc<Term>, span: Span) {
+        self.0.insert(Rc::as_ptr(t), span);
+    }
+}
+
+/// `#` comment, from `#` to the end of the line (excluding the newline).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Comment {
+    pub span: Span,
+    /// Whether there is code before it on the same line.
+    pub trailing: bool,
+}
+
+/// 1-based line and column (in characters) of a byte offset.
+pub fn line_col(s: &str, offset: usize) -> (usize, usize) {
+    let before = &s[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    (line, before[line_start..].chars().count() + 1)
+}
+
+// Source line containing the offset with a caret under it:
+//    |
+//  3 | `k z
+//    |    ^ label
+pub(crate) fn excerpt(s: &str, offset: usize, label: &str) -> String {
+    let (line, column) = line_col(s, offset);
+    let line_start = s[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = s[offset..].find('\n').map_or(s.len(), |i| offset + i);
+    let text = s[line_start..line_end].trim_end_matches('\r');
+    let gutter = " ".repeat(line.to_string().len());
+    // preserve tabs so that the caret lines up
+    let padding: String = text.chars().take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!("{} |\n{} | {}\n{} | {}^ {}\n", gutter, line, text, gutter, padding, label)
+}
+
+pub fn parse_str(s: &str) -> Result<Rc<Term>, ParseError> {
+    parse(s, None, None)
+}
+
+/// Also records the span of every node.
+pub fn parse_with_spans(s: &str) -> Result<(Rc<Term>, SpanMap), ParseError> {
+    let mut spans = SpanMap::default();
+    let t = parse(s, Some(&mut spans), None)?;
+    Ok((t, spans))
+}
+
+/// Also records the span of every node and the comments, in source order.
+pub fn parse_with_comments(s: &str) -> Result<(Rc<Term>, SpanMap, Vec<Comment>), ParseError> {
+    let mut spans = SpanMap::default();
+    let mut comments = Vec::new();
+    let t = parse(s, Some(&mut spans), Some(&mut comments))?;
+    Ok((t, spans, comments))
+}
+
+fn parse(
+        s: &str,
+        mut spans: Option<&mut SpanMap>,
+        mut comments: Option<&mut Vec<Comment>>) -> Result<Rc<Term>, ParseError> {
+    // Offsets of backticks and their already parsed left subtrees.
+    let mut path: Vec<(usize, Option<Rc<Term>>)> = Vec::new();
+    let mut it = s.char_indices();
+    let err = |kind, offset, path: &[_]| Err(ParseError::new(kind, s, offset, path));
     let result;
     'outer: loop {
-        let leaf = Rc::new(match it.next() {
-            None => return Err("unexpected EOF".to_owned()),
-            Some('`') => {
-                path.push(None);
+        let (offset, c) = match it.next() {
+            None => return err(ParseErrorKind::UnexpectedEof, s.len(), &path),
+            Some(p) => p,
+        };
+        let leaf = Term::new(match c {
+            '`' => {
+                path.push((offset, None));
                 continue;
             }
-            Some('k') => K,
-            Some('s') => S,
-            Some('i') => I,
-            Some('v') => V,
-            Some('d') => D,
-            Some('e') => E,
-            Some('c') => C,
-            Some('.') => Print(it.next().ok_or("unexpected EOF after '.'")?),
-            Some('r') => Print('\n'),
-            Some('@') => Read,
-            Some('?') => CompareRead(it.next().ok_or("unexpected EOF after '?'")?),
-            Some('|') => Reprint,
-            Some('#') => {
-                skip_comment(&mut it);
+            'k' => K,
+            's' => S,
+            'i' => I,
+            'v' => V,
+            'd' => D,
+            'e' => E,
+            'c' => C,
+            '.' => match it.next() {
+                Some((_, c)) => Print(c),
+                None => return err(ParseErrorKind::UnexpectedEofAfter('.'), s.len(), &path),
+            }
+            'r' => Print('\n'),
+            '@' => Read,
+            '?' => match it.next() {
+                Some((_, c)) => CompareRead(c),
+                None => return err(ParseErrorKind::UnexpectedEofAfter('?'), s.len(), &path),
+            }
+            '|' => Reprint,
+            '#' => {
+                let end = skip_comment(&mut it);
+                if let Some(ref mut comments) = comments {
+                    comments.push(comment(s, offset, end));
+                }
                 continue;
             }
-            Some(c) if c.is_whitespace() => continue,
-            Some(c) => return Err(format!("unrecognized {:?}", c)),
+            c if c.is_whitespace() => continue,
+            c => return err(ParseErrorKind::Unrecognized(c), offset, &path),
         });
+        // all nodes completed by this leaf end where it ends
+        let end = it.offset();
+        if let Some(ref mut spans) = spans {
+            spans.insert(&leaf, Span { start: offset, end });
+        }
         let mut subtree = leaf;
         loop {
             match path.pop() {
@@ -39,44 +233,130 @@ pub fn parse_str(s: &str) -> Result<Rc<Term>, String> {
                     result = subtree;
                     break 'outer;
                 }
-                Some(None) => {
-                    path.push(Some(subtree));
+                Some((offset, None)) => {
+                    path.push((offset, Some(subtree)));
                     break;
                 }
-                Some(Some(left)) => subtree = Rc::new(Apply(left, subtree)),
+                Some((start, Some(left))) => {
+                    subtree = Term::new(Apply(left, subtree));
+                    if let Some(ref mut spans) = spans {
+                        spans.insert(&subtree, Span { start, end });
+                    }
+                }
             }
         }
     }
 
-    while let Some(c) = it.next() {
+    while let Some((offset, c)) = it.next() {
         match c {
-            '#' => skip_comment(&mut it),
+            '#' => {
+                let end = skip_comment(&mut it);
+                if let Some(ref mut comments) = comments {
+                    comments.push(comment(s, offset, end));
+                }
+            }
             c if c.is_whitespace() => {}
-            c => return Err(format!("unexpected {:?}", c)),
+            c => return err(ParseErrorKind::Unexpected(c), offset, &path),
         }
     }
 
     Ok(result)
 }
 
-fn skip_comment(it: &mut dyn Iterator<Item=char>) {
-    while it.next().unwrap_or('\n') != '\n' {}
+// Returns the end of the comment, the newline is consumed but not included.
+fn skip_comment(it: &mut CharIndices) -> usize {
+    loop {
+        let end = it.offset();
+        if let None | Some((_, '\n')) = it.next() {
+            return end;
+        }
+    }
+}
+
+fn comment(s: &str, start: usize, end: usize) -> Comment {
+    let line_start = s[..start].rfind('\n').map_or(0, |i| i + 1);
+    Comment {
+        span: Span { start, end },
+        trailing: !s[line_start..start].trim().is_empty(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::ParseErrorKind::*;
 
     #[test]
     fn errors() {
-        assert_eq!(parse_str("").unwrap_err(), "unexpected EOF");
-        assert_eq!(parse_str("  ").unwrap_err(), "unexpected EOF");
-        assert_eq!(parse_str("`k").unwrap_err(), "unexpected EOF");
-        assert_eq!(parse_str(".").unwrap_err(), "unexpected EOF after '.'");
-        assert_eq!(parse_str("`s?").unwrap_err(), "unexpected EOF after '?'");
-
-        assert_eq!(parse_str("z").unwrap_err(), "unrecognized 'z'");
-        assert_eq!(parse_str("`kks").unwrap_err(), "unexpected 's'");
+        let kind = |s| parse_str(s).unwrap_err().kind;
+        assert_eq!(kind(""), UnexpectedEof);
+        assert_eq!(kind("  "), UnexpectedEof);
+        assert_eq!(kind("`k"), UnexpectedEof);
+        assert_eq!(kind("."), UnexpectedEofAfter('.'));
+        assert_eq!(kind("`s?"), UnexpectedEofAfter('?'));
+
+        assert_eq!(kind("z"), Unrecognized('z'));
+        assert_eq!(kind("`kks"), Unexpected('s'));
+
+        assert_eq!(parse_str("z").unwrap_err().to_string(), "1:1: unrecognized 'z'");
+        assert_eq!(parse_str("`kks").unwrap_err().to_string(), "1:4: unexpected 's'");
+    }
+
+    #[test]
+    fn error_positions() {
+        let e = parse_str("``k # comment\n  `v\tz").unwrap_err();
+        assert_eq!(e.kind, Unrecognized('z'));
+        assert_eq!((e.offset, e.line, e.column), (19, 2, 6));
+        assert_eq!(e.unmatched, [0, 1, 16]);
+        assert_eq!(e.render("``k # comment\n  `v\tz"), "\
+error: unrecognized 'z'
+  |
+2 |   `v\tz
+  |     \t^ here
+note: 3 unmatched '`', innermost at 2:3
+  |
+2 |   `v\tz
+  |   ^ this application is incomplete
+");
+
+        let e = parse_str("`k`k\n").unwrap_err();
+        assert_eq!(e.kind, UnexpectedEof);
+        assert_eq!((e.offset, e.line, e.column), (5, 2, 1));
+        assert_eq!(e.unmatched, [0, 2]);
+
+        let e = parse_str("`ks z").unwrap_err();
+        assert_eq!(e.unmatched, []);
+    }
+
+    #[test]
+    fn spans() {
+        let s = "`.x # comment\n  ``kvr";
+        let (t, spans) = parse_with_spans(s).unwrap();
+        assert_eq!(spans.len(), 7);
+        let text = |t: &Term| {
+            let span = spans.get(t).unwrap();
+            &s[span.start..span.end]
+        };
+        assert_eq!(text(&t), s);
+        let Apply(ref f, ref x) = *t else { panic!() };
+        assert_eq!(text(f), ".x");
+        assert_eq!(text(x), "``kvr");
+        let Apply(ref f, _) = **x else { panic!() };
+        assert_eq!(text(f), "`kv");
+
+        assert!(spans.get(&Term::new(K)).is_none());
+        assert_eq!(spans.get(f).unwrap().render(s, "here"), "at 2:4\n  |\n2 |   ``kvr\n  |    ^ here\n");
+    }
+
+    #[test]
+    fn comments() {
+        let s = "# header\n`k  # trailing\n  # own line\n  v # last";
+        let (t, _, comments) = parse_with_comments(s).unwrap();
+        assert_eq!(t.to_string(), "`kv");
+        let texts: Vec<_> = comments.iter().map(|c| (&s[c.span.start..c.span.end], c.trailing)).collect();
+        assert_eq!(texts, [("# header", false), ("# trailing", true), ("# own line", false), ("# last", true)]);
+        // `.#` prints '#'
+        assert!(parse_with_comments("`.#i").unwrap().2.is_empty());
     }
 
     #[test]
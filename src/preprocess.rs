@@ -0,0 +1,448 @@
+// Named definitions, expanded into plain Unlambda before parsing:
+//
+//     let twice = ``s``s`kski    # comments are fine
+//     let ab = `.a.b
+//     ``$twice $ab i
+//
+// Definitions come first, each body being a single expression.
+// `$name` can be used wherever a combinator can, including other bodies.
+// Sources without definitions are returned unchanged.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use crate::Term;
+use crate::parser::{self, excerpt, line_col, ParseError, Span, SpanMap};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PreprocessErrorKind {
+    /// `let` or `$` not followed by a name.
+    ExpectedName,
+    /// `let name` not followed by `=`.
+    ExpectedEquals,
+    /// EOF before the end of the body.
+    IncompleteDefinition(String),
+    Redefined(String),
+    Undefined(String),
+    /// Names of definitions referring to each other,
+    /// the first one is repeated at the end.
+    Cycle(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreprocessError {
+    pub kind: PreprocessErrorKind,
+    /// The offending name or reference (the whole definition if incomplete).
+    pub span: Span,
+    /// Earlier definition, for `Redefined`.
+    pub previous: Option<Span>,
+}
+
+impl std::fmt::Display for PreprocessErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            PreprocessErrorKind::ExpectedName => write!(f, "expected a name"),
+            PreprocessErrorKind::ExpectedEquals => write!(f, "expected '='"),
+            PreprocessErrorKind::IncompleteDefinition(ref name) => write!(f, "definition of {} is incomplete", name),
+            PreprocessErrorKind::Redefined(ref name) => write!(f, "{} is already defined", name),
+            PreprocessErrorKind::Undefined(ref name) => write!(f, "{} is not defined", name),
+            PreprocessErrorKind::Cycle(ref names) => write!(f, "cyclic definitions: {}", names.join(" -> ")),
+        }
+    }
+}
+
+impl std::fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+impl PreprocessError {
+    fn new(kind: PreprocessErrorKind, start: usize, end: usize) -> Self {
+        PreprocessError { kind, span: Span { start, end }, previous: None }
+    }
+
+    /// Human-readable message with the relevant source lines.
+    pub fn render(&self, s: &str) -> String {
+        let mut result = format!("error: {}\n", self.kind);
+        result.push_str(&excerpt(s, self.span.start, "here"));
+        if let Some(previous) = self.previous {
+            let (line, column) = line_col(s, previous.start);
+            result.push_str(&format!("note: previously defined at {}:{}\n", line, column));
+            result.push_str(&excerpt(s, previous.start, "previous definition"));
+        }
+        result
+    }
+}
+
+/// Plain Unlambda source produced by `expand()`,
+/// remembering where each piece of it came from.
+#[derive(Debug)]
+pub struct Expansion {
+    pub text: String,
+    // (offset in text, offset in source) of contiguous pieces
+    segments: Vec<(usize, usize)>,
+    source_len: usize,
+}
+
+impl Expansion {
+    fn push(&mut self, s: &str, start: usize, end: usize) {
+        if start < end {
+            self.segments.push((self.text.len(), start));
+            self.text.push_str(&s[start..end]);
+        }
+    }
+
+    /// Maps an offset in `text` to the source, text expanded
+    /// from a reference maps to the body of the definition.
+    pub fn source_offset(&self, offset: usize) -> usize {
+        if offset >= self.text.len() {
+            return self.source_len;
+        }
+        let i = self.segments.partition_point(|&(o, _)| o <= offset) - 1;
+        let (text_start, source_start) = self.segments[i];
+        source_start + offset - text_start
+    }
+
+    /// Parses `text`, positions in errors and spans refer to `source`.
+    pub fn parse_with_spans(&self, source: &str) -> Result<(Rc<Term>, SpanMap), ParseError> {
+        match parser::parse_with_spans(&self.text) {
+            Ok((t, mut spans)) => {
+                spans.map_offsets(|o| self.source_offset(o));
+                Ok((t, spans))
+            }
+            Err(mut e) => {
+                e.offset = self.source_offset(e.offset);
+                (e.line, e.column) = line_col(source, e.offset);
+                for offset in e.unmatched.iter_mut() {
+                    *offset = self.source_offset(*offset);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+struct Reference {
+    name: String,
+    // including `$`
+    span: Span,
+}
+
+struct Definition {
+    name: Span,
+    body: Span,
+    references: Vec<Reference>,
+}
+
+struct Scanner<'s> {
+    s: &'s str,
+    pos: usize,
+}
+
+impl Scanner<'_> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_comment(&mut self) {
+        while self.bump().is_some_and(|c| c != '\n') {}
+    }
+
+    // the rest of the line if it's blank, including the newline
+    fn skip_line_end(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => return self.skip_comment(),
+                '\n' => {
+                    self.bump();
+                    return;
+                }
+                c if c.is_whitespace() => { self.bump(); }
+                _ => return,
+            }
+        }
+    }
+
+    // whitespace and comments
+    fn skip_blank(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => self.skip_comment(),
+                c if c.is_whitespace() => { self.bump(); }
+                _ => break,
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<Span, PreprocessError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.bump();
+        }
+        if self.pos == start {
+            return Err(PreprocessError::new(PreprocessErrorKind::ExpectedName, start, start));
+        }
+        Ok(Span { start, end: self.pos })
+    }
+
+    // after `$`
+    fn reference(&mut self, start: usize) -> Result<Reference, PreprocessError> {
+        let name = self.name()?;
+        Ok(Reference {
+            name: self.s[name.start..name.end].to_string(),
+            span: Span { start, end: name.end },
+        })
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        let rest = &self.s[self.pos..];
+        rest.starts_with(keyword) && rest[keyword.len()..].starts_with(char::is_whitespace)
+    }
+
+    // Scans a single expression, returns false on EOF.
+    fn expression(&mut self, references: &mut Vec<Reference>) -> Result<bool, PreprocessError> {
+        let mut needed = 1;
+        while needed > 0 {
+            self.skip_blank();
+            let start = self.pos;
+            match self.bump() {
+                None => return Ok(false),
+                Some('`') => needed += 1,
+                Some('.' | '?') => {
+                    if self.bump().is_none() {
+                        return Ok(false);
+                    }
+                    needed -= 1;
+                }
+                Some('$') => {
+                    references.push(self.reference(start)?);
+                    needed -= 1;
+                }
+                // unrecognized characters are left to the parser
+                Some(_) => needed -= 1,
+            }
+        }
+        Ok(true)
+    }
+
+    // Scans the rest of the source.
+    fn references(&mut self, references: &mut Vec<Reference>) -> Result<(), PreprocessError> {
+        while let Some(c) = self.bump() {
+            match c {
+                '#' => self.skip_comment(),
+                '.' | '?' => { self.bump(); }
+                '$' => references.push(self.reference(self.pos - 1)?),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces references with the bodies of definitions
+/// and removes the definitions.
+pub fn expand(s: &str) -> Result<Expansion, PreprocessError> {
+    let mut definitions: HashMap<&str, Definition> = HashMap::new();
+    let mut order = Vec::new();
+    let mut sc = Scanner { s, pos: 0 };
+    // after the line of the last definition, so that comments before main are kept
+    let mut main_start = 0;
+    loop {
+        sc.skip_blank();
+        if !sc.is_keyword("let") {
+            break;
+        }
+        let start = sc.pos;
+        sc.pos += "let".len();
+        sc.skip_blank();
+        let name = sc.name()?;
+        sc.skip_blank();
+        if sc.bump() != Some('=') {
+            let pos = name.end;
+            return Err(PreprocessError::new(PreprocessErrorKind::ExpectedEquals, pos, pos));
+        }
+        sc.skip_blank();
+        let body_start = sc.pos;
+        let mut references = Vec::new();
+        let name_str = &s[name.start..name.end];
+        if !sc.expression(&mut references)? {
+            let kind = PreprocessErrorKind::IncompleteDefinition(name_str.to_string());
+            return Err(PreprocessError::new(kind, start, sc.pos));
+        }
+        if let Some(previous) = definitions.get(name_str) {
+            let mut e = PreprocessError::new(
+                PreprocessErrorKind::Redefined(name_str.to_string()), name.start, name.end);
+            e.previous = Some(previous.name);
+            return Err(e);
+        }
+        let body = Span { start: body_start, end: sc.pos };
+        definitions.insert(name_str, Definition { name, body, references });
+        order.push(name_str);
+        sc.skip_line_end();
+        main_start = sc.pos;
+    }
+
+    sc.pos = main_start;
+    let mut main_references = Vec::new();
+    sc.references(&mut main_references)?;
+
+    let mut done = HashSet::new();
+    for name in &order {
+        check_cycles(name, &definitions, &mut Vec::new(), &mut done)?;
+    }
+    for r in &main_references {
+        if !definitions.contains_key(r.name.as_str()) {
+            let kind = PreprocessErrorKind::Undefined(r.name.clone());
+            return Err(PreprocessError::new(kind, r.span.start, r.span.end));
+        }
+    }
+
+    let mut result = Expansion {
+        text: String::new(),
+        segments: Vec::new(),
+        source_len: s.len(),
+    };
+    emit(s, Span { start: main_start, end: s.len() }, &main_references, &definitions, &mut result);
+    Ok(result)
+}
+
+// Depth-first search from the definition, `path` holds the names being expanded
+// and `done` the ones already checked, so that each one is visited once.
+// Also reports undefined names.
+fn check_cycles<'s>(
+        name: &'s str,
+        definitions: &'s HashMap<&'s str, Definition>,
+        path: &mut Vec<&'s str>,
+        done: &mut HashSet<&'s str>) -> Result<(), PreprocessError> {
+    if done.contains(name) {
+        return Ok(());
+    }
+    path.push(name);
+    for r in &definitions[name].references {
+        let Some((&next, _)) = definitions.get_key_value(r.name.as_str()) else {
+            let kind = PreprocessErrorKind::Undefined(r.name.clone());
+            return Err(PreprocessError::new(kind, r.span.start, r.span.end));
+        };
+        if let Some(i) = path.iter().position(|&n| n == next) {
+            let mut names: Vec<String> = path[i..].iter().map(|n| n.to_string()).collect();
+            names.push(next.to_string());
+            let kind = PreprocessErrorKind::Cycle(names);
+            return Err(PreprocessError::new(kind, r.span.start, r.span.end));
+        }
+        check_cycles(next, definitions, path, done)?;
+    }
+    path.pop();
+    done.insert(name);
+    Ok(())
+}
+
+fn emit(
+        s: &str,
+        range: Span,
+        references: &[Reference],
+        definitions: &HashMap<&str, Definition>,
+        result: &mut Expansion) {
+    let mut pos = range.start;
+    for r in references {
+        result.push(s, pos, r.span.start);
+        let d = &definitions[r.name.as_str()];
+        emit(s, d.body, &d.references, definitions, result);
+        pos = r.span.end;
+    }
+    result.push(s, pos, range.end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::PreprocessErrorKind::*;
+
+    #[test]
+    fn expansion() {
+        let s = "let ab = `.a.b  # prints ab\nlet x=`$ab$ab\n`$x `.$i";
+        let e = expand(s).unwrap();
+        assert_eq!(e.text, "```.a.b`.a.b `.$i");
+        assert_eq!(e.source_offset(0), s.find("`$x").unwrap());
+        assert_eq!(e.source_offset(1), s.find("`$ab").unwrap());
+        assert_eq!(e.source_offset(2), s.find("`.a.b").unwrap());
+        assert_eq!(e.source_offset(e.text.len()), s.len());
+
+        // standard Unlambda is left alone
+        let s = "```s.$ii # $foo\n.$";
+        assert_eq!(expand(s).unwrap().text, s);
+        assert_eq!(expand("lettuce").unwrap().text, "lettuce");
+        let s = "# header\n\n`.ai  # comment\n";
+        assert_eq!(expand(s).unwrap().text, s);
+        let s = "let a = i\n# about main\n$a";
+        assert_eq!(expand(s).unwrap().text, "# about main\ni");
+    }
+
+    #[test]
+    fn shared_definitions() {
+        // each definition refers to the previous one twice,
+        // checking every path would take forever
+        let mut s = String::from("let a0 = i\n");
+        for n in 1..100 {
+            s.push_str(&format!("let a{} = `$a{} $a{}\n", n, n - 1, n - 1));
+        }
+        s.push('k');
+        assert_eq!(expand(&s).unwrap().text, "k");
+    }
+
+    #[test]
+    fn errors() {
+        let error = |s| expand(s).unwrap_err();
+        assert_eq!(error("let = i").kind, ExpectedName);
+        assert_eq!(error("let a i").kind, ExpectedEquals);
+        assert_eq!(error("`$ i").kind, ExpectedName);
+        assert_eq!(error("let a = `i\n").kind, IncompleteDefinition("a".to_string()));
+        assert_eq!(error("let a = `$b i\n$a").kind, Undefined("b".to_string()));
+        assert_eq!(error("$a").kind, Undefined("a".to_string()));
+
+        let s = "let a = i\nlet a = v\n$a";
+        let e = error(s);
+        assert_eq!(e.kind, Redefined("a".to_string()));
+        assert_eq!((e.span, e.previous), (Span { start: 14, end: 15 }, Some(Span { start: 4, end: 5 })));
+        assert_eq!(e.render(s), "\
+error: a is already defined
+  |
+2 | let a = v
+  |     ^ here
+note: previously defined at 1:5
+  |
+1 | let a = i
+  |     ^ previous definition
+");
+
+        let e = error("let a = `$b i\nlet b = $c\nlet c = `$a $a\n$a");
+        assert_eq!(e.kind, Cycle(vec!["a".into(), "b".into(), "c".into(), "a".into()]));
+        assert_eq!(e.span, Span { start: 34, end: 36 });
+        assert_eq!(error("let a = $a i").kind, Cycle(vec!["a".into(), "a".into()]));
+    }
+
+    #[test]
+    fn parsing() {
+        let s = "let k2 = `kk\n`$k2 z";
+        let e = expand(s).unwrap().parse_with_spans(s).unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (18, 2, 6));
+
+        let s = "let k2 = `kk\n``$k2 i";
+        let e = expand(s).unwrap().parse_with_spans(s).unwrap_err();
+        assert_eq!((e.line, e.column, e.unmatched.clone()), (2, 8, vec![13]));
+
+        let s = "let k2 = `kk\n``$k2 iv";
+        let (t, spans) = expand(s).unwrap().parse_with_spans(s).unwrap();
+        assert_eq!(t.to_string(), "```kkiv");
+        let Term::Apply(ref f, _) = *t else { panic!() };
+        let Term::Apply(ref k2, _) = **f else { panic!() };
+        assert_eq!(spans.get(k2), Some(Span { start: 9, end: 12 }));
+    }
+}
@@ -0,0 +1,373 @@
+// Pretty-printing of programs, see `unlambda fmt`.
+//
+// An application that fits in the width is printed on one line,
+// otherwise its backticks and function come first and then
+// each argument on its own line, indented:
+//
+//     ```s
+//       `kk
+//       `.a.b
+//       i
+//
+// The indentation stops growing at half the width, so that deeply
+// nested programs still fit.
+//
+// Comments are kept before the term following them,
+// or at the end of the line if they were after code.
+//
+// `minify()` does the opposite, for code golf.
+// `to_source()` also prints runtime values.
+
+use crate::Term;
+use crate::Term::*;
+use crate::parser::{self, Comment, ParseError, Span, SpanMap};
+
+pub const DEFAULT_WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+#[derive(Clone, Debug)]
+pub struct PrettyPrinter {
+    width: usize,
+    comments: bool,
+}
+
+impl Default for PrettyPrinter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrettyPrinter {
+    pub fn new() -> Self {
+        PrettyPrinter {
+            width: DEFAULT_WIDTH,
+            comments: true,
+        }
+    }
+
+    /// Maximum line length in characters, only exceeded
+    /// when a single combinator doesn't fit after the indentation.
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// Whether `format_source()` keeps comments (the default).
+    pub fn with_comments(mut self, comments: bool) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    /// Formats a term, e.g. a parsed program. Ends with a newline.
+    pub fn format(&self, t: &Term) -> String {
+        let mut p = Layout::new(self.width, None, &[]);
+        p.term(t, 0);
+        p.finish()
+    }
+
+    /// Parses and formats a program (`let` definitions are not supported,
+    /// see `preprocess::expand()`).
+    pub fn format_source(&self, source: &str) -> Result<String, ParseError> {
+        let (t, spans, comments) = parser::parse_with_comments(source)?;
+        let comments = if self.comments { &comments[..] } else { &[] };
+        let mut p = Layout::new(self.width, Some((&spans, source)), comments);
+        p.term(&t, 0);
+        Ok(p.finish())
+    }
+}
+
+struct Layout<'a> {
+    width: usize,
+    out: String,
+    source: Option<(&'a SpanMap, &'a str)>,
+    // not printed yet
+    comments: &'a [Comment],
+}
+
+impl<'a> Layout<'a> {
+    fn new(width: usize, source: Option<(&'a SpanMap, &'a str)>, comments: &'a [Comment]) -> Self {
+        Layout {
+            width,
+            out: String::new(),
+            source,
+            comments,
+        }
+    }
+
+    fn span(&self, t: &Term) -> Option<Span> {
+        self.source.and_then(|(spans, _)| spans.get(t))
+    }
+
+    fn term(&mut self, t: &Term, indent: usize) {
+        // arguments still to print, last one first
+        let mut stack = vec![(t, indent)];
+        while let Some((t, indent)) = stack.pop() {
+            let span = self.span(t);
+            if let Some(span) = span {
+                self.comments_before(span.start, indent);
+            }
+            // comments have to end lines
+            let has_comments = span.is_some_and(|span| {
+                self.comments.first().is_some_and(|c| c.span.start < span.end)
+            });
+            if !has_comments && fits(t, self.width.saturating_sub(indent)) {
+                self.line(indent, &to_source(t).unwrap_or_else(|_| t.to_string()));
+                continue;
+            }
+            let mut args = 0;
+            let mut head = t;
+            // deep nesting stays within the width instead of growing quadratically
+            let arg_indent = if indent >= self.width / 2 { indent } else { indent + INDENT };
+            while let Apply(ref f, ref x) = *head {
+                args += 1;
+                stack.push((x, arg_indent));
+                head = f;
+            }
+            if let Some(span) = self.span(head) {
+                self.comments_before(span.start, indent);
+            }
+            self.line(indent, &format!("{}{}", "`".repeat(args), head));
+        }
+    }
+
+    fn comments_before(&mut self, offset: usize, indent: usize) {
+        while let Some((c, rest)) = self.comments.split_first() {
+            if c.span.start >= offset {
+                break;
+            }
+            self.comments = rest;
+            let text = self.source.unwrap().1[c.span.start..c.span.end].trim_end();
+            if c.trailing && !self.out.is_empty() {
+                self.out.push_str("  ");
+                self.out.push_str(text);
+            } else {
+                self.line(indent, text);
+            }
+        }
+    }
+
+    fn line(&mut self, indent: usize, text: &str) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.out.extend(std::iter::repeat_n(' ', indent));
+        self.out.push_str(text);
+    }
+
+    fn finish(mut self) -> String {
+        self.comments_before(usize::MAX, 0);
+        self.out.push('\n');
+        self.out
+    }
+}
+
+/// Source of a parsed program in as few characters as possible:
+/// without whitespace and comments, and with `r` instead of `.`
+/// followed by a newline.
+///
+/// Returns None if it wouldn't parse back to the same tree,
+/// which is the case for runtime values such as `k1(x)` (see `to_source()`).
+pub fn minify(t: &Term) -> Option<String> {
+    // the same as `t.to_string()` for source terms
+    let text = to_source(t).ok()?;
+    let parsed = parser::parse_str(&text).ok()?;
+    if same(t, &parsed) { Some(text) } else { None }
+}
+
+/// A value with a continuation captured by `c` in it,
+/// which can't be written in Unlambda.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContinuationError;
+
+impl std::fmt::Display for ContinuationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the value contains a continuation, which has no source form")
+    }
+}
+
+impl std::error::Error for ContinuationError {}
+
+/// Unlambda source evaluating to the same value, e.g. `` `kx `` for `k1(x)`,
+/// ``` ``sxy ``` for `s2(x, y)` and `` `dx `` for `promise(x)`,
+/// so that results can be saved and run again.
+pub fn to_source(t: &Term) -> Result<String, ContinuationError> {
+    let mut s = String::new();
+    // prefix notation, so children can be written as they're popped
+    let mut stack = vec![t];
+    while let Some(t) = stack.pop() {
+        match *t {
+            Apply(ref f, ref x) => {
+                s.push('`');
+                stack.push(x);
+                stack.push(f);
+            }
+            K1(ref x) => {
+                s.push_str("`k");
+                stack.push(x);
+            }
+            S1(ref x) => {
+                s.push_str("`s");
+                stack.push(x);
+            }
+            S2(ref x, ref y) => {
+                s.push_str("``s");
+                stack.push(y);
+                stack.push(x);
+            }
+            Promise(ref x) => {
+                s.push_str("`d");
+                stack.push(x);
+            }
+            Cont(_) | ReifiedCont(_) => return Err(ContinuationError),
+            _ => s.push_str(&t.to_string()),
+        }
+    }
+    Ok(s)
+}
+
+/// `to_source()` for printing results: values with continuations are
+/// displayed instead, followed by the reason, e.g.
+/// `<cont> (the value contains a continuation, which has no source form)`.
+pub fn to_source_or_display(t: &Term) -> String {
+    to_source(t).unwrap_or_else(|e| format!("{} ({})", t, e))
+}
+
+// Structural equality of source terms.
+fn same(a: &Term, b: &Term) -> bool {
+    let mut stack = vec![(a, b)];
+    while let Some(pair) = stack.pop() {
+        match pair {
+            (Apply(f1, x1), Apply(f2, x2)) => {
+                stack.push((x1, x2));
+                stack.push((f1, f2));
+            }
+            (Print(c1), Print(c2)) | (CompareRead(c1), CompareRead(c2)) if c1 == c2 => {}
+            (K, K) | (S, S) | (I, I) | (V, V) | (D, D) | (E, E) | (C, C)
+                | (Read, Read) | (Reprint, Reprint) => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+// Whether `t.to_string()` has at most `width` characters,
+// without formatting all of it.
+fn fits(t: &Term, width: usize) -> bool {
+    let mut left = width;
+    let mut stack = vec![t];
+    while let Some(t) = stack.pop() {
+        let len = match *t {
+            Apply(ref f, ref x) => {
+                stack.push(x);
+                stack.push(f);
+                1
+            }
+            _ => t.to_string().chars().count(),
+        };
+        match left.checked_sub(len) {
+            Some(l) => left = l,
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use crate::Ctx;
+    use crate::parser::parse_str;
+    use crate::small_step::full_eval;
+
+    #[test]
+    fn layout() {
+        let p = PrettyPrinter::new().with_width(10);
+        assert_eq!(p.format(&parse_str("``s`kki").unwrap()), "``s`kki\n");
+        assert_eq!(p.format(&parse_str("```s`kk`.a.bi").unwrap()), "\
+```s
+  `kk
+  `.a.b
+  i
+");
+        // arguments are broken further when needed
+        assert_eq!(p.format(&parse_str("``s``s`k.a`k.bi").unwrap()), "\
+``s
+  ``s
+    `k.a
+    `k.b
+  i
+");
+        // a combinator alone may exceed the width
+        assert_eq!(PrettyPrinter::new().with_width(0).format(&parse_str("`.ar").unwrap()), "`.a\nr\n");
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let depth = 60000;
+        let t = parse_str(&format!("{}i", "`.a".repeat(depth))).unwrap();
+        let formatted = PrettyPrinter::new().with_width(10).format(&t);
+        // the indentation stops at half the width
+        assert!(formatted.lines().all(|l| l.len() <= 10), "{}", &formatted[..200]);
+        assert!(same(&parse_str(&formatted).unwrap(), &t));
+        let flat = PrettyPrinter::new().with_width(usize::MAX).format(&t);
+        assert_eq!(flat.len(), 3 * depth + 2);
+    }
+
+    #[test]
+    fn minified() {
+        let t = parse_str("``s # comment\n  `k.\n . ").unwrap();
+        assert_eq!(minify(&t).unwrap(), "``s`kr. ");
+        let t = parse_str("```?\n.#|`@c").unwrap();
+        assert_eq!(minify(&t).unwrap(), "```?\n.#|`@c");
+        assert!(minify(&Term::new(K1(Term::new(I)))).is_none());
+        let deep = format!("{}i", "`.a".repeat(60000));
+        assert_eq!(minify(&parse_str(&deep).unwrap()).unwrap(), deep);
+
+        assert!(same(&parse_str("`.\ni").unwrap(), &parse_str("`ri").unwrap()));
+        assert!(!same(&parse_str("`.ai").unwrap(), &parse_str("`.bi").unwrap()));
+        assert!(!same(&parse_str("``kii").unwrap(), &parse_str("`k`ii").unwrap()));
+    }
+
+    #[test]
+    fn runtime_values() {
+        let i = || Term::new(I);
+        let ka = Term::new(K1(parse_str(".a").unwrap()));
+        assert_eq!(to_source(&ka), Ok("`k.a".to_string()));
+        let t = Term::new(S2(Term::new(S1(Rc::clone(&ka))), Term::new(Promise(parse_str("`.bi").unwrap()))));
+        assert_eq!(t.to_string(), "s2(s1(k1(.a)), promise(`.bi))");
+        assert_eq!(to_source(&t), Ok("``s`s`k.a`d`.bi".to_string()));
+        assert_eq!(to_source(&Term::new(Apply(ka, i()))), Ok("``k.ai".to_string()));
+
+        let cont = Term::new(ReifiedCont(rc_stack::RcStack::new()));
+        let kc = Term::new(K1(cont));
+        assert_eq!(to_source(&kc), Err(ContinuationError));
+        assert_eq!(to_source_or_display(&kc), format!("k1(<cont>) ({})", ContinuationError));
+        assert_eq!(to_source_or_display(&t), "``s`s`k.a`d`.bi");
+
+        // evaluating the source gives the same value
+        let eval = |s: &str| {
+            full_eval(parse_str(s).unwrap(), &mut Ctx::new(&mut std::io::sink(), &mut "".chars())).unwrap()
+        };
+        let value = eval("``s`ks`k`i.a");
+        assert_eq!(value.to_string(), "s2(k1(s), k1(.a))");
+        let source = to_source(&value).unwrap();
+        assert_eq!(source, "``s`ks`k.a");
+        assert_eq!(eval(&source).to_string(), value.to_string());
+    }
+
+    #[test]
+    fn comments() {
+        let s = "# header\n`k  # trailing\n  # own line\n  v # last\n";
+        let p = PrettyPrinter::new();
+        assert_eq!(p.format_source(s).unwrap(), "# header\n`k  # trailing\n  # own line\n  v  # last\n");
+        assert_eq!(p.clone().with_comments(false).format_source(s).unwrap(), "`kv\n");
+        // comments outside applications don't break them
+        assert_eq!(p.format_source("`ki # done\n\n# end").unwrap(), "`ki  # done\n# end\n");
+
+        for s in [s, "``s # x\n`kk # y\ni", "```s#a\n`kk#b\n`.#.##c\n`ii"] {
+            let formatted = PrettyPrinter::new().with_width(4).format_source(s).unwrap();
+            assert_eq!(parse_str(&formatted).unwrap().to_string(), parse_str(s).unwrap().to_string(), "{}", formatted);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+// Interactive mode, `unlambda repl`.
+//
+// Expressions and the input read by `@` come from the same stream,
+// so `@` consumes the lines following the expression.
+
+use std::cell::RefCell;
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
+use unlambda::{interpreter, parser, preprocess, CancelToken, Ctx, Encoding, Halt, Interpreter, Term};
+use unlambda::parser::ParseErrorKind;
+use unlambda::pretty;
+use unlambda::stats::Stats;
+use unlambda::trace::{Event, Record, TextTracer, Tracer};
+
+const HELP: &str = "\
+Enter an expression to evaluate it, it may span several lines.
+Commands:
+    :trace          toggle tracing to stderr
+    :stats          toggle statistics after each evaluation
+    :interp [NAME]  show or switch the interpreter
+    :load FILE      evaluate a program from a file, `let` definitions are allowed
+    :help           show this message
+    :quit           exit (so does EOF)
+Ctrl-C stops the evaluation.
+";
+
+// Installed as the tracer for the whole session,
+// so that tracing can be switched while the context holds it.
+#[derive(Default)]
+struct Session {
+    trace: Option<TextTracer<'static>>,
+    stats: Option<Stats>,
+    // whether program output ended with a newline
+    at_line_start: bool,
+}
+
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Session>>);
+
+impl Tracer for Shared {
+    fn record(&mut self, r: &Record) {
+        let mut session = self.0.borrow_mut();
+        if let Event::Output(c) = r.event {
+            session.at_line_start = c == '\n';
+        }
+        if let Some(ref mut trace) = session.trace {
+            trace.record(r);
+        }
+        if let Some(ref mut stats) = session.stats {
+            stats.record(r);
+        }
+    }
+}
+
+// Lets the program and the REPL itself write to the same stream.
+struct SharedWriter<'a, 'w>(&'a RefCell<&'w mut dyn Write>);
+
+impl Write for SharedWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+// Lets the program and the REPL itself read from the same stream.
+// Only buffers one byte, which is all that decoding a character needs.
+struct SharedReader<'a, 'r> {
+    input: &'a RefCell<&'r mut dyn BufRead>,
+    byte: [u8; 1],
+}
+
+impl Read for SharedReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.borrow_mut().read(buf)
+    }
+}
+
+impl BufRead for SharedReader<'_, '_> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        match self.input.borrow_mut().fill_buf()?.first() {
+            Some(&b) => {
+                self.byte[0] = b;
+                Ok(&self.byte)
+            }
+            None => Ok(&[]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.input.borrow_mut().consume(amt)
+    }
+}
+
+/// `interrupt` is cancelled on Ctrl-C, it is reset before each evaluation.
+pub fn run(
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+        mut interp: &'static dyn Interpreter,
+        encoding: Encoding,
+        interrupt: &CancelToken) -> std::io::Result<()> {
+    let input = RefCell::new(input);
+    let output = RefCell::new(output);
+    let mut program_output = SharedWriter(&output);
+    let mut program_input = SharedReader { input: &input, byte: [0] };
+    let session = Shared::default();
+    let mut tracer = session.clone();
+    let mut show_stats = false;
+
+    // persists between entries, so does the character read by `@`
+    let mut ctx = Ctx::with_encoding(&mut program_output, &mut program_input, encoding);
+    ctx.set_tracer(Some(&mut tracer));
+    ctx.set_cancel_token(Some(interrupt.clone()));
+
+    let mut out = SharedWriter(&output);
+    let mut buf = String::new();
+    let mut open = 0;
+    loop {
+        if buf.is_empty() {
+            write!(out, "> ")?;
+        } else {
+            // the number of applications still waiting for arguments
+            write!(out, "{}> ", "`".repeat(open))?;
+        }
+        out.flush()?;
+        let mut bytes = Vec::new();
+        if input.borrow_mut().read_until(b'\n', &mut bytes)? == 0 {
+            if !buf.is_empty() {
+                writeln!(out)?;
+                write!(out, "{}", parser::parse_str(&buf).unwrap_err().render(&buf))?;
+            }
+            break;
+        }
+        let line = match encoding.decode(bytes) {
+            Ok(line) => line,
+            Err(e) => {
+                writeln!(out, "error: {}", e)?;
+                continue;
+            }
+        };
+
+        if buf.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+                let arg = arg.trim();
+                match name {
+                    "trace" => {
+                        let mut session = session.0.borrow_mut();
+                        session.trace = match session.trace {
+                            Some(_) => None,
+                            None => Some(TextTracer::new(Box::new(std::io::stderr()), 60)),
+                        };
+                        writeln!(out, "tracing {}", if session.trace.is_some() { "on" } else { "off" })?;
+                    }
+                    "stats" => {
+                        show_stats = !show_stats;
+                        writeln!(out, "statistics {}", if show_stats { "on" } else { "off" })?;
+                    }
+                    "interp" if arg.is_empty() => {
+                        let names: Vec<_> = interpreter::all().iter().map(|i| i.name()).collect();
+                        writeln!(out, "{} (available: {})", interp.name(), names.join(", "))?;
+                    }
+                    "interp" => match interpreter::find(arg) {
+                        Some(i) => interp = i,
+                        None => writeln!(out, "error: unrecognized interpreter {:?}", arg)?,
+                    },
+                    "load" => match std::fs::read(arg).and_then(|bytes| encoding.decode(bytes)) {
+                        Ok(source) => match load(&source) {
+                            Ok(program) => {
+                                session.0.borrow_mut().at_line_start = true;
+                                evaluate(program, interp, &mut ctx, &session, show_stats, interrupt, &mut out)?;
+                            }
+                            Err(message) => write!(out, "{}", message)?,
+                        },
+                        Err(e) => writeln!(out, "error: {}: {}", arg, e)?,
+                    },
+                    "help" => write!(out, "{}", HELP)?,
+                    "quit" | "q" => break,
+                    _ => writeln!(out, "error: unknown command :{}, try :help", name)?,
+                }
+                continue;
+            }
+        }
+
+        buf.push_str(&line);
+        match parser::parse_str(&buf) {
+            Err(e) if e.kind == ParseErrorKind::UnexpectedEof && e.unmatched.is_empty() => {
+                // only whitespace and comments so far
+                buf.clear();
+            }
+            Err(e) if matches!(e.kind, ParseErrorKind::UnexpectedEof | ParseErrorKind::UnexpectedEofAfter(_)) => {
+                open = e.unmatched.len();
+            }
+            Err(e) => {
+                write!(out, "{}", e.render(&buf))?;
+                buf.clear();
+            }
+            Ok(program) => {
+                // the expression was entered, the cursor is at the line start
+                session.0.borrow_mut().at_line_start = true;
+                evaluate(program, interp, &mut ctx, &session, show_stats, interrupt, &mut out)?;
+                buf.clear();
+            }
+        }
+    }
+    Ok(())
+}
+
+// With `let` definitions, errors are rendered.
+fn load(source: &str) -> Result<Rc<Term>, String> {
+    let expansion = preprocess::expand(source).map_err(|e| e.render(source))?;
+    match expansion.parse_with_spans(source) {
+        Ok((program, _)) => Ok(program),
+        Err(e) => Err(e.render(source)),
+    }
+}
+
+fn evaluate(
+        program: Rc<Term>,
+        interp: &dyn Interpreter,
+        ctx: &mut Ctx,
+        session: &Shared,
+        show_stats: bool,
+        interrupt: &CancelToken,
+        out: &mut dyn Write) -> std::io::Result<()> {
+    if let Err(e) = interp.check(&program) {
+        return writeln!(out, "error: {}", e);
+    }
+    session.0.borrow_mut().stats = if show_stats { Some(Stats::new()) } else { None };
+    interrupt.reset();
+    let result = interp.eval(program, ctx);
+
+    let mut session = session.0.borrow_mut();
+    if !session.at_line_start {
+        writeln!(out)?;
+    }
+    // as source if possible, so that it can be entered again
+    match result {
+        Ok(t) => writeln!(out, "{}", pretty::to_source_or_display(&t))?,
+        Err(Halt::Exit(t)) => writeln!(out, "exited with {}", pretty::to_source_or_display(&t))?,
+        Err(Halt::Io(e)) => writeln!(out, "error: {}", e)?,
+        Err(Halt::Cancelled(_)) => writeln!(out, "interrupted")?,
+        // no limits are set
+        Err(h) => unreachable!("{:?}", h),
+    }
+    if let Some(stats) = session.stats.take() {
+        stats.report(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(input: &str) -> String {
+        String::from_utf8(session_with(input.as_bytes(), Encoding::Utf8, &CancelToken::new())).unwrap()
+    }
+
+    fn session_with(mut input: &[u8], encoding: Encoding, interrupt: &CancelToken) -> Vec<u8> {
+        let mut output = Vec::new();
+        run(&mut input, &mut output, interpreter::default(), encoding, interrupt).unwrap();
+        output
+    }
+
+    #[test]
+    fn entries() {
+        assert_eq!(session("`.ai\n``ks\n  v\n# comment\n"), "> a\ni\n> `> s\n> > ");
+        assert!(session("``kv\n").starts_with("> `> \nerror: unexpected EOF\n"));
+        assert!(session("`kv z\n").contains("error: unexpected 'z'"));
+    }
+
+    #[test]
+    fn shared_input() {
+        // `@` reads the line after the expression, `|` remembers it across entries
+        assert_eq!(session("`@i\nx`|i\n"), "> i\n> .x\n> ");
+        assert_eq!(session("`ev\n"), "> exited with v\n> ");
+        // characters are decoded
+        assert_eq!(session("`@i\né`|i\n"), "> i\n> .é\n> ");
+        let output = session_with(b"`@i\n\xe9`|i\n", Encoding::Bytes, &CancelToken::new());
+        assert_eq!(output, "> i\n> .é\n> ".as_bytes());
+        // results are printed as source
+        assert_eq!(session("``s`kki\n`ci\n"), "> ``s`kki\n> <cont> (the value contains a continuation, which has no source form)\n> ");
+    }
+
+    #[test]
+    fn commands() {
+        let output = session(":interp\n:interp cps\n:interp\n:interp foo\n:nope\n:quit\n`ki\n");
+        assert_eq!(output, "\
+> smallstep (available: metacircular, cps, smallstep)
+> > cps (available: metacircular, cps, smallstep)
+> error: unrecognized interpreter \"foo\"
+> error: unknown command :nope, try :help
+> ");
+        assert!(session(":stats\n`ki\n").contains("applications: 1\n"));
+        assert!(session(":interp metacircular\n`ci\n").contains("does not support call/cc"));
+    }
+
+    #[test]
+    fn load() {
+        let path = std::env::temp_dir().join(format!("unlambda-repl-{}.unl", std::process::id()));
+        std::fs::write(&path, "let a = `.ai  # prints a\n``$a$a k\n").unwrap();
+        let output = session(&format!(":load {}\n:load {}x\n", path.display(), path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(output.starts_with("> aa\nk\n> error: "), "{}", output);
+    }
+
+    #[test]
+    fn interrupt() {
+        let interrupt = CancelToken::new();
+        // cancelled before, it's reset when evaluation starts
+        interrupt.cancel();
+        let token = interrupt.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            token.cancel();
+        });
+        let output = session_with(b"```sii``sii\n``kii\n", Encoding::Utf8, &interrupt);
+        thread.join().unwrap();
+        assert_eq!(output, b"> interrupted\n> i\n> ");
+
+        // the metacircular interpreter would overflow the stack on ```sii``sii,
+        // so it computes `i applied 40^4 times
+        let numeral = |n: usize| format!("{}i", "``s``s`ksk".repeat(n - 1));
+        let input = format!(":interp metacircular\n```{}{}ii\n``kii\n", numeral(4), numeral(40));
+        let token = interrupt.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            token.cancel();
+        });
+        let output = session_with(input.as_bytes(), Encoding::Utf8, &interrupt);
+        thread.join().unwrap();
+        assert_eq!(output, b"> > interrupted\n> i\n> ");
+    }
+}
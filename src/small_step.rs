@@ -1,7 +1,11 @@
 use crate::Ctx;
 use crate::EvalResult;
+use crate::{Halt, Suspended};
+use crate::Interpreter;
+use crate::trace::Event;
 use crate::Term;
 use crate::Term::*;
+use std::cell::Ref;
 use std::rc::Rc;
 use rc_stack::RcStack;
 
@@ -34,12 +38,17 @@ Recursion  eval -> eval_of_apply -> eval  is implemented as a loop in eval().
 */
 
 fn resume(mut cont: Cont, value: Rc<Term>, ctx: &mut Ctx) -> ContResult {
-    match cont.pop_clone() {
+    let entry = cont.pop_clone();
+    if ctx.tracer.is_some() {
+        ctx.depth = Some(cont.len());
+    }
+    match entry {
         None /* cont0 */ => Err(Ok(value)),
         Some(Cont1(ref x)) => {
             let ef = value;
             if let D = *ef {
-                Ok((cont, Rc::new(Promise(Rc::clone(x)))))
+                ctx.trace(Event::Promise { x });
+                Ok((cont, Term::new(Promise(Rc::clone(x)))))
             } else {
                 cont.push(Cont2(ef));
                 eval(Rc::clone(x), cont)
@@ -73,46 +82,49 @@ fn apply(f: Rc<Term>, x: Rc<Term>, cont: Cont, ctx: &mut Ctx) -> ContResult {
     if let Apply(_, _) = *x {
         panic!();
     }
+    ctx.steps += 1;
+    ctx.trace(Event::Apply { f: &f, x: &x });
 
     let result = match *f {
-        K => Rc::new(K1(x)),
+        K => Term::new(K1(x)),
         K1(ref y) => Rc::clone(y),
-        S => Rc::new(S1(x)),
-        S1(ref y) => Rc::new(S2(Rc::clone(y), x)),
+        S => Term::new(S1(x)),
+        S1(ref y) => Term::new(S2(Rc::clone(y), x)),
 
         S2(ref y, ref z) => {
             return eval_of_apply(
-                Rc::new(Apply(Rc::clone(y), Rc::clone(&x))),
-                Rc::new(Apply(Rc::clone(z), Rc::clone(&x))), cont);
+                Term::new(Apply(Rc::clone(y), Rc::clone(&x))),
+                Term::new(Apply(Rc::clone(z), Rc::clone(&x))), cont);
         }
 
         Print(c) => {
-            ctx.output.write_fmt(format_args!("{}", c)).unwrap();
+            if let Err(h) = ctx.write_char(c) {
+                return Err(Err(h));
+            }
             x
         }
         I => x,
         V => f,
-        E => return Err(Err(x)),
+        E => return Err(Err(Halt::Exit(x))),
         Read => {
-            let c = ctx.input.next();
-            ctx.cur_char = c;
-            let t = match c {
-                Some(_) => Rc::new(I),
-                None => Rc::new(V),
+            let t = match ctx.read_char() {
+                Ok(Some(_)) => Term::new(I),
+                Ok(None) => Term::new(V),
+                Err(h) => return Err(Err(h)),
             };
             return eval_of_apply(x, t, cont);
         }
         CompareRead(c) => {
             let t = match ctx.cur_char {
-                Some(cc) if cc == c => Rc::new(I),
-                _ => Rc::new(V),
+                Some(cc) if cc == c => Term::new(I),
+                _ => Term::new(V),
             };
             return eval_of_apply(x, t, cont);
         }
         Reprint => {
             let t = match ctx.cur_char {
-                Some(c) => Rc::new(Print(c)),
-                None => Rc::new(V),
+                Some(c) => Term::new(Print(c)),
+                None => Term::new(V),
             };
             return eval_of_apply(x, t, cont);
         }
@@ -123,10 +135,12 @@ fn apply(f: Rc<Term>, x: Rc<Term>, cont: Cont, ctx: &mut Ctx) -> ContResult {
         }
 
         C => {
-            let c = Rc::new(Term::ReifiedCont(RcStack::clone(&cont)));
+            ctx.trace(Event::Capture);
+            let c = Term::new(ReifiedCont(RcStack::clone(&cont)));
             return eval_of_apply(x, c, cont);
         }
         ReifiedCont(ref cont) => {
+            ctx.trace(Event::Invoke { x: &x });
             return Ok((RcStack::clone(cont), x));
         }
 
@@ -137,14 +151,242 @@ fn apply(f: Rc<Term>, x: Rc<Term>, cont: Cont, ctx: &mut Ctx) -> ContResult {
     Ok((cont, result))
 }
 
+/// Explicit state of the interpreter that can be advanced
+/// one step at a time, paused and resumed later.
+pub struct Machine {
+    // Some((cont, value)) means "value is to be passed to cont".
+    state: Option<(Cont, Rc<Term>)>,
+    result: Option<EvalResult>,
+    steps: u64,
+}
+
+impl Machine {
+    pub fn new(term: Rc<Term>) -> Self {
+        let mut m = Machine {
+            state: None,
+            result: None,
+            steps: 0,
+        };
+        match eval(term, RcStack::new()) {
+            Ok(state) => m.state = Some(state),
+            Err(result) => m.result = Some(result),
+        }
+        m
+    }
+
+    /// Returns false if there was nothing to do because
+    /// the evaluation is already finished, or because `@` is to be applied
+    /// and the device has no input available (see `io::Queue`).
+    pub fn step(&mut self, ctx: &mut Ctx) -> bool {
+        let reads = matches!(self.next_function().as_deref(), Some(Read));
+        if reads {
+            match ctx.input_ready() {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(h) => {
+                    self.state = None;
+                    self.result = Some(Err(h));
+                    return true;
+                }
+            }
+        }
+        match self.state.take() {
+            Some((cont, value)) => {
+                self.steps += 1;
+                match resume(cont, value, ctx) {
+                    Ok(state) => self.state = Some(state),
+                    Err(result) => self.result = Some(result),
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs until finished, until a limit set in `ctx` is reached
+    /// or until it has to wait for input.
+    pub fn run(mut self: Box<Self>, ctx: &mut Ctx) -> EvalResult {
+        loop {
+            if self.is_finished() {
+                return self.result.unwrap();
+            }
+            if let Some(halt) = ctx.check_limits() {
+                return Err(halt(Suspended::SmallStep(self)));
+            }
+            // only steps that apply a function count
+            if ctx.out_of_fuel() && self.next_function().is_some() {
+                return Err(Halt::OutOfFuel(Suspended::SmallStep(self)));
+            }
+            let printed = if ctx.yield_on_output {
+                match self.next_function().as_deref() {
+                    Some(&Print(c)) => Some(c),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            if !self.step(ctx) {
+                return Err(Halt::NeedsInput(Suspended::SmallStep(self)));
+            }
+            if let Some(c) = printed {
+                if !self.is_finished() {
+                    return Err(Halt::Output(c, Suspended::SmallStep(self)));
+                }
+            }
+        }
+    }
+
+    /// Performs at most `n` steps, returns how many were actually performed.
+    /// Limits set in `ctx` are not enforced.
+    pub fn run_for(&mut self, n: u64, ctx: &mut Ctx) -> u64 {
+        let mut done = 0;
+        while done < n && self.step(ctx) {
+            done += 1;
+        }
+        done
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_none()
+    }
+
+    /// Number of steps performed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Evaluated term that is about to be passed to the continuation,
+    /// or None if finished.
+    pub fn term(&self) -> Option<&Rc<Term>> {
+        self.state.as_ref().map(|s| &s.1)
+    }
+
+    // Function applied by the next step, if it's an application.
+    fn next_function(&self) -> Option<Ref<'_, Term>> {
+        Ref::filter_map(self.cont()?.peek()?, |entry| match *entry {
+            Cont2(ref f) => Some(&**f),
+            Cont1(_) => None,
+        }).ok()
+    }
+
+    pub fn cont(&self) -> Option<&Cont> {
+        self.state.as_ref().map(|s| &s.0)
+    }
+
+    /// Continuation entries, innermost first.
+    pub fn cont_entries(&self) -> Vec<ContEntry> {
+        self.cont().map_or_else(Vec::new, Cont::to_vec)
+    }
+
+    pub fn result(&self) -> Option<&EvalResult> {
+        self.result.as_ref()
+    }
+
+    pub fn into_result(self) -> Option<EvalResult> {
+        self.result
+    }
+}
+
 pub fn full_eval(term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
-    let cont0 = RcStack::new();
-    let mut r: ContResult = eval(term, cont0);
-    loop {
-        match r {
-            Ok((cont, term)) =>
-                r = resume(cont, term, ctx),
-            Err(result) => return result,
+    Box::new(Machine::new(term)).run(ctx)
+}
+
+pub struct SmallStep;
+
+impl Interpreter for SmallStep {
+    fn name(&self) -> &'static str { "smallstep" }
+    fn supports_call_cc(&self) -> bool { true }
+    fn supports_stepping(&self) -> bool { true }
+    fn supports_resuming(&self) -> bool { true }
+
+    fn run(&self, term: Rc<Term>, ctx: &mut Ctx) -> EvalResult {
+        full_eval(term, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Queue;
+    use crate::parser::parse_str;
+
+    #[test]
+    fn pause_and_resume() {
+        let program = parse_str("`.a`.b`.ci").unwrap();
+        let mut buf = Vec::<u8>::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+
+        let mut m = Machine::new(program);
+        assert!(!m.is_finished());
+        assert_eq!(m.term().unwrap().to_string(), ".a");
+        assert_eq!(m.cont_entries().len(), 1);
+
+        assert_eq!(m.run_for(4, &mut ctx), 4);
+        assert!(!m.is_finished());
+        assert!(m.result().is_none());
+
+        let n = m.run_for(1000, &mut ctx);
+        assert!(n < 1000);
+        assert!(m.is_finished());
+        assert!(m.term().is_none());
+        assert!(!m.step(&mut ctx));
+        assert_eq!(m.steps(), 4 + n);
+        assert_eq!(m.into_result().unwrap().unwrap().to_string(), "i");
+        drop(ctx);
+        assert_eq!(buf, b"cba");
+    }
+
+    #[test]
+    fn suspend_for_input() {
+        // prompts and echoes a character, twice
+        let program = parse_str("```|`@`.>ii``|`@`.>ii").unwrap();
+        let mut io = Queue::new();
+        let suspended = match full_eval(Rc::clone(&program), &mut Ctx::with_io(&mut io)) {
+            Err(Halt::NeedsInput(s)) => s,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(io.take_output(), ">");
+        io.push_input("a");
+        let r = suspended.resume(&mut Ctx::with_io(&mut io));
+        assert!(matches!(r, Some(Err(Halt::NeedsInput(_)))), "{:?}", r);
+        assert_eq!(io.take_output(), "a>");
+        io.close();
+        let r = match r {
+            Some(Err(Halt::NeedsInput(s))) => s.resume(&mut Ctx::with_io(&mut io)),
+            _ => unreachable!(),
+        };
+        assert!(matches!(r, Some(Ok(_))), "{:?}", r);
+
+        // the step is performed once input arrives
+        let mut io = Queue::new();
+        let mut ctx = Ctx::with_io(&mut io);
+        let mut m = Machine::new(parse_str("`@i").unwrap());
+        assert!(m.step(&mut ctx));
+        assert!(!m.step(&mut ctx));
+        assert_eq!(m.steps(), 1);
+        drop(ctx);
+        io.push_input("x");
+        let mut ctx = Ctx::with_io(&mut io);
+        assert!(m.step(&mut ctx));
+        assert_eq!(m.steps(), 2);
+    }
+
+    #[test]
+    fn yield_on_output() {
+        let mut output = Vec::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut output, &mut input);
+        ctx.set_yield_on_output(true);
+        let mut printed = String::new();
+        let mut r = full_eval(parse_str("`.a`.b`.ci").unwrap(), &mut ctx);
+        while let Err(Halt::Output(c, s)) = r {
+            printed.push(c);
+            r = s.resume(&mut ctx).unwrap();
         }
+        assert_eq!(r.unwrap().to_string(), "i");
+        assert_eq!(printed, "cba");
+        drop(ctx);
+        assert_eq!(output, b"cba");
     }
 }
@@ -0,0 +1,113 @@
+// Counters collected from trace events, see `--stats`.
+
+use std::io::Write;
+use crate::memory;
+use crate::trace::{Event, Record, Tracer};
+
+/// Combinators in the order they are reported,
+/// partial applications (`k1`, `s1`, `s2`) are counted as `k` and `s`,
+/// `d` counts promises created.
+pub const COMBINATORS: [&str; 11] = ["k", "s", "i", "v", "d", "c", "e", ".x", "@", "?x", "|"];
+
+/// Evaluation statistics, install it with `Ctx::set_tracer()`.
+#[derive(Debug)]
+pub struct Stats {
+    /// All applications, including those of promises and continuations.
+    pub total_applications: u64,
+    /// Indexed like `COMBINATORS`.
+    pub applications: [u64; COMBINATORS.len()],
+    pub promises_forced: u64,
+    pub continuations_captured: u64,
+    pub continuations_invoked: u64,
+    /// Only known for interpreters with explicit continuations (small-step).
+    pub peak_depth: Option<usize>,
+    pub peak_live_terms: usize,
+    allocated_at_start: u64,
+}
+
+impl Stats {
+    /// Allocations are counted from this moment,
+    /// see `memory::enable_accounting()`.
+    pub fn new() -> Self {
+        memory::enable_accounting();
+        Stats {
+            total_applications: 0,
+            applications: [0; COMBINATORS.len()],
+            promises_forced: 0,
+            continuations_captured: 0,
+            continuations_invoked: 0,
+            peak_depth: None,
+            peak_live_terms: memory::live_terms(),
+            allocated_at_start: memory::allocated_terms(),
+        }
+    }
+
+    /// Applications of the combinator (one of `COMBINATORS`).
+    pub fn applications_of(&self, combinator: &str) -> u64 {
+        COMBINATORS.iter().position(|&c| c == combinator)
+            .map_or(0, |i| self.applications[i])
+    }
+
+    /// Terms allocated since `new()`.
+    pub fn allocations(&self) -> u64 {
+        memory::allocated_terms() - self.allocated_at_start
+    }
+
+    fn count(&mut self, combinator: &str) {
+        let combinator = match combinator {
+            "k1" => "k",
+            "s1" | "s2" => "s",
+            c => c,
+        };
+        if let Some(i) = COMBINATORS.iter().position(|&c| c == combinator) {
+            self.applications[i] += 1;
+        }
+    }
+
+    pub fn report(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "applications: {}", self.total_applications)?;
+        for (c, n) in COMBINATORS.iter().zip(self.applications) {
+            if *c != "d" {
+                writeln!(out, "  {:<2} {}", c, n)?;
+            }
+        }
+        writeln!(out, "promises created: {}", self.applications_of("d"))?;
+        writeln!(out, "promises forced: {}", self.promises_forced)?;
+        writeln!(out, "continuations captured: {}", self.continuations_captured)?;
+        writeln!(out, "continuations invoked: {}", self.continuations_invoked)?;
+        match self.peak_depth {
+            Some(depth) => writeln!(out, "peak continuation depth: {}", depth)?,
+            None => writeln!(out, "peak continuation depth: n/a")?,
+        }
+        writeln!(out, "peak live terms: {}", self.peak_live_terms)?;
+        writeln!(out, "terms allocated: {}", self.allocations())
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tracer for Stats {
+    fn record(&mut self, r: &Record) {
+        match r.event {
+            Event::Apply { f, .. } => {
+                self.total_applications += 1;
+                match crate::trace::combinator(f) {
+                    "promise" => self.promises_forced += 1,
+                    "<cont>" => self.continuations_invoked += 1,
+                    c => self.count(c),
+                }
+            }
+            Event::Promise { .. } => self.count("d"),
+            Event::Capture => self.continuations_captured += 1,
+            Event::Invoke { .. } | Event::Output(_) | Event::Input(_) => {}
+        }
+        if let Some(depth) = r.depth {
+            self.peak_depth = Some(self.peak_depth.map_or(depth, |d| d.max(depth)));
+        }
+        self.peak_live_terms = self.peak_live_terms.max(memory::live_terms());
+    }
+}
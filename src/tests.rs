@@ -2,24 +2,25 @@ use super::*;
 use crate::parser::parse_str;
 
 fn run_and_expect(
-        eval: &dyn Fn(Rc<Term>, &mut Ctx) -> EvalResult,
+        interp: &dyn Interpreter,
         program: &str,
         result: Option<&str>,
         output: Option<&str>) {
-    run_with_input_and_expect(eval, program, "", result, output, None);
+    run_with_input_and_expect(interp, program, "", result, output, None);
 }
 
 fn run_with_input_and_expect(
-        eval: &dyn Fn(Rc<Term>, &mut Ctx) -> EvalResult,
+        interp: &dyn Interpreter,
         program: &str, input: &str,
         result: Option<&str>, output: Option<&str>, remaining_input: Option<&str>) {
     let mut buf = Vec::<u8>::new();
     let mut input_it = input.chars();
     let actual_result = {
         let mut ctx = Ctx::new(&mut buf, &mut input_it);
-        eval(parse_str(program).unwrap(), &mut ctx)
-            .unwrap_or_else(|e| e)
-            .to_string()
+        match interp.eval(parse_str(program).unwrap(), &mut ctx) {
+            Ok(t) | Err(Halt::Exit(t)) => t.to_string(),
+            Err(h) => panic!("{:?}", h),
+        }
     };
     if let Some(result) = result {
         assert_eq!(&actual_result, result);
@@ -35,8 +36,7 @@ fn run_with_input_and_expect(
 
 #[test]
 fn test_eval() {
-    let evals = [metacircular::eval, cps::full_eval, small_step::full_eval];
-    for eval in &evals {
+    for &eval in interpreter::all() {
         run_and_expect(eval, "s", Some("s"), None);
         run_and_expect(eval, "s", Some("s"), None);
 
@@ -69,8 +69,7 @@ fn test_eval() {
 
 #[test]
 fn test_input() {
-    let evals = [metacircular::eval, cps::full_eval, small_step::full_eval];
-    for eval in &evals {
+    for &eval in interpreter::all() {
         run_with_input_and_expect(eval, "@", "zzz", None, None, Some("zzz"));
 
         run_with_input_and_expect(eval, "`@i", "", Some("v"), None, Some(""));
@@ -85,8 +84,7 @@ fn test_input() {
 
 #[test]
 fn call_cc() {
-    let evals = [cps::full_eval, small_step::full_eval];
-    for eval in &evals {
+    for &eval in interpreter::all().iter().filter(|i| i.supports_call_cc()) {
         // from http://www.madore.org/~david/programs/unlambda/#callcc
         run_and_expect(eval, "``cir", Some("r"), Some("\n"));
         run_and_expect(eval, "`c``s`kr``si`ki", Some("i"), Some(""));
@@ -95,8 +93,7 @@ fn call_cc() {
 
 #[test]
 fn ramanujan() {
-    let evals = [metacircular::eval, cps::full_eval, small_step::full_eval];
-    for eval in &evals {
+    for &eval in interpreter::all() {
         // http://www.madore.org/~david/programs/unlambda/#howto_num
         let mut expected = "*".repeat(1729);
         expected.push('\n');
@@ -110,3 +107,337 @@ fn ramanujan() {
             None, Some(&expected));
     }
 }
+
+#[test]
+fn out_of_fuel() {
+    for &interp in interpreter::all() {
+        let mut buf = Vec::<u8>::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        ctx.set_max_steps(Some(1000));
+        let r = interp.eval(parse_str("```sii``sii").unwrap(), &mut ctx);
+        assert!(matches!(r, Err(Halt::OutOfFuel(_))));
+        assert_eq!(ctx.steps(), 1000);
+    }
+}
+
+#[test]
+fn exact_step_limit() {
+    for program in ["i", "`ii", "``kii", "```s`k.a`kii", "``d`.ai.b", "`c`.ai"] {
+        let mut counts = Vec::new();
+        for &interp in interpreter::all().iter().filter(|i| i.check(&parse_str(program).unwrap()).is_ok()) {
+            let run = |max_steps| {
+                let mut buf = Vec::<u8>::new();
+                let mut input = "".chars();
+                let mut ctx = Ctx::new(&mut buf, &mut input);
+                ctx.set_max_steps(max_steps);
+                let r = interp.eval(parse_str(program).unwrap(), &mut ctx);
+                (r, ctx.steps())
+            };
+            let (r, steps) = run(None);
+            assert!(r.is_ok(), "{} {:?}", interp.name(), r);
+            counts.push(steps);
+            let (r, _) = run(Some(steps));
+            assert!(r.is_ok(), "{} {} {:?}", program, interp.name(), r);
+            if steps > 0 {
+                let (r, _) = run(Some(steps - 1));
+                assert!(matches!(r, Err(Halt::OutOfFuel(_))), "{} {} {:?}", program, interp.name(), r);
+            }
+        }
+        // the engines agree on the number of applications
+        assert!(counts.windows(2).all(|w| w[0] == w[1]), "{} {:?}", program, counts);
+    }
+}
+
+#[test]
+fn resume_after_out_of_fuel() {
+    let program = "``````````````.H.e.l.l.o.,. .w.o.r.l.d.!rv";
+    for &interp in interpreter::all() {
+        let mut buf = Vec::<u8>::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        ctx.set_max_steps(Some(5));
+        let suspended = match interp.eval(parse_str(program).unwrap(), &mut ctx) {
+            Err(Halt::OutOfFuel(s)) => s,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(ctx.steps(), 5);
+        ctx.set_max_steps(None);
+        match suspended.resume(&mut ctx) {
+            Some(r) => {
+                assert!(interp.supports_resuming());
+                assert_eq!(r.unwrap().to_string(), "v");
+                drop(ctx);
+                assert_eq!(std::str::from_utf8(&buf).unwrap(), "Hello, world!\n");
+            }
+            None => assert!(!interp.supports_resuming()),
+        }
+    }
+}
+
+#[test]
+fn out_of_memory() {
+    // h = \s.\x. s s (k x), ``hhi builds an infinite chain of k1(...)
+    let program = "````s``si``s`kk``sii`kk``s``si``s`kk``sii`kki";
+    for &interp in interpreter::all() {
+        let mut buf = Vec::<u8>::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        ctx.set_max_steps(Some(1_000_000));
+        ctx.set_max_memory(Some(memory::usage() + 10_000));
+        let r = interp.eval(parse_str(program).unwrap(), &mut ctx);
+        assert!(matches!(r, Err(Halt::OutOfMemory(_))), "{:?}", r);
+    }
+}
+
+struct Failing;
+
+impl std::io::Write for Failing {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::io::Read for Failing {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::ConnectionReset.into())
+    }
+}
+
+#[test]
+fn io_errors() {
+    for &interp in interpreter::all() {
+        let (mut output, mut input) = (Failing, "".chars());
+        let mut ctx = Ctx::new(&mut output, &mut input);
+        let r = interp.eval(parse_str("``.ai`ei").unwrap(), &mut ctx);
+        assert!(matches!(r, Err(Halt::Io(ref e)) if e.kind() == std::io::ErrorKind::BrokenPipe), "{:?}", r);
+
+        let (mut buf, mut input) = (Vec::<u8>::new(), std::io::BufReader::new(Failing));
+        let mut ctx = Ctx::with_encoding(&mut buf, &mut input, Encoding::Utf8);
+        let r = interp.eval(parse_str("``@i.a").unwrap(), &mut ctx);
+        assert!(matches!(r, Err(Halt::Io(ref e)) if e.kind() == std::io::ErrorKind::ConnectionReset), "{:?}", r);
+        drop(ctx);
+        assert!(buf.is_empty());
+    }
+}
+
+#[test]
+fn trace_errors() {
+    use crate::trace::{JsonTracer, TextTracer, Tracer};
+
+    // counts the attempts to write
+    struct Full(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl std::io::Write for Full {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            self.0.set(self.0.get() + 1);
+            Err(std::io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    for json in [false, true] {
+        let writes = std::rc::Rc::new(std::cell::Cell::new(0));
+        let out = Box::new(Full(writes.clone()));
+        // the header of JSON traces is the first write
+        let mut tracer: Box<dyn Tracer> = if json {
+            Box::new(JsonTracer::new(out))
+        } else {
+            Box::new(TextTracer::new(out, 60))
+        };
+        let (mut output, mut input) = (Vec::new(), "".chars());
+        let mut ctx = Ctx::new(&mut output, &mut input);
+        ctx.set_tracer(Some(&mut *tracer));
+        let r = small_step::SmallStep.eval(parse_str("```s`kk`ki.a").unwrap(), &mut ctx);
+        drop(ctx);
+        // evaluation goes on without tracing
+        assert!(r.is_ok(), "{:?}", r);
+        assert_eq!(writes.get(), 1);
+        assert_eq!(tracer.finish().unwrap_err().kind(), std::io::ErrorKind::StorageFull);
+        assert!(tracer.finish().is_ok());
+    }
+}
+
+#[test]
+fn io_devices() {
+    // prompts, reads a character and echoes it
+    let program = "``|`@`.?ii";
+    for &interp in interpreter::all() {
+        let mut buffers = io::Buffers::new("ab");
+        let mut ctx = Ctx::with_io(&mut buffers);
+        interp.eval(parse_str(program).unwrap(), &mut ctx).unwrap();
+        drop(ctx);
+        assert_eq!(buffers.output(), "?a");
+        assert_eq!(buffers.remaining_input(), "b");
+
+        let mut script = io::Scripted::new().output("?").input("a").output("a");
+        let r = interp.eval(parse_str(program).unwrap(), &mut Ctx::with_io(&mut script));
+        assert!(r.is_ok(), "{:?}", r);
+        assert!(script.is_finished());
+
+        // the program has to prompt before reading
+        let mut script = io::Scripted::new().input("a");
+        let r = interp.eval(parse_str(program).unwrap(), &mut Ctx::with_io(&mut script));
+        assert!(matches!(r, Err(Halt::Io(_))), "{:?}", r);
+    }
+}
+
+#[test]
+fn cancellation() {
+    for &interp in interpreter::all() {
+        let token = CancelToken::new();
+        token.cancel();
+        let mut buf = Vec::<u8>::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        ctx.set_cancel_token(Some(token));
+        let r = interp.eval(parse_str("```sii``sii").unwrap(), &mut ctx);
+        assert!(matches!(r, Err(Halt::Cancelled(_))), "{:?}", r);
+    }
+}
+
+#[test]
+fn cancellation_from_another_thread() {
+    // metacircular interpreter would overflow the stack first
+    for &interp in interpreter::all().iter().filter(|i| i.supports_call_cc()) {
+        let token = CancelToken::new();
+        let t = std::thread::spawn({
+            let token = token.clone();
+            move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                token.cancel();
+            }
+        });
+        let mut buf = Vec::<u8>::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        ctx.set_cancel_token(Some(token));
+        let r = interp.eval(parse_str("```sii``sii").unwrap(), &mut ctx);
+        t.join().unwrap();
+        assert!(matches!(r, Err(Halt::Cancelled(_))), "{:?}", r);
+    }
+}
+
+#[test]
+fn timeout() {
+    for &interp in interpreter::all().iter().filter(|i| i.supports_call_cc()) {
+        let mut buf = Vec::<u8>::new();
+        let mut input = "".chars();
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        let start = std::time::Instant::now();
+        ctx.set_deadline(Some(start + std::time::Duration::from_millis(50)));
+        let r = interp.eval(parse_str("```sii``sii").unwrap(), &mut ctx);
+        assert!(matches!(r, Err(Halt::TimedOut(_))), "{:?}", r);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+    }
+}
+
+struct RecordingTracer(Vec<String>);
+
+impl trace::Tracer for RecordingTracer {
+    fn record(&mut self, r: &trace::Record) {
+        let details = match r.event {
+            trace::Event::Apply { f, x } => format!("{} {}", f, x),
+            trace::Event::Promise { x } | trace::Event::Invoke { x } => x.to_string(),
+            trace::Event::Capture => String::new(),
+            trace::Event::Output(c) => format!("{:?}", c),
+            trace::Event::Input(c) => format!("{:?}", c),
+        };
+        self.0.push(format!("{} {} {}", r.step, r.event.name(), details));
+    }
+}
+
+fn trace_of(interp: &dyn Interpreter, program: &str, input: &str) -> Vec<String> {
+    let mut buf = Vec::<u8>::new();
+    let mut input = input.chars();
+    let mut tracer = RecordingTracer(Vec::new());
+    {
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        ctx.set_tracer(Some(&mut tracer));
+        interp.eval(parse_str(program).unwrap(), &mut ctx).unwrap();
+    }
+    tracer.0
+}
+
+#[test]
+fn tracing() {
+    assert_eq!(trace_of(&small_step::SmallStep, "``d`.ai`@i", "x"), [
+        "0 promise `.ai",
+        "1 apply @ i",
+        "1 input Some('x')",
+        "2 apply i i",
+        "3 apply promise(`.ai) i",
+        "4 apply .a i",
+        "4 output 'a'",
+        "5 apply i i",
+    ]);
+    assert_eq!(trace_of(&small_step::SmallStep, "``cir", ""), [
+        "1 apply c i",
+        "1 capture ",
+        "2 apply i <cont>",
+        "3 apply <cont> r",
+        "3 invoke r",
+        "4 apply r r",
+        "4 output '\\n'",
+    ]);
+
+    // all interpreters do the same thing in the same order
+    for program in ["``d`.ai`@i", "```s`kdri", "``````````````.H.e.l.l.o.,. .w.o.r.l.d.!rv"] {
+        let expected = trace_of(&small_step::SmallStep, program, "x");
+        for &interp in interpreter::all() {
+            assert_eq!(trace_of(interp, program, "x"), expected, "{}", interp.name());
+        }
+    }
+    let expected = trace_of(&small_step::SmallStep, "`c``s`kr``si`ki", "");
+    assert_eq!(trace_of(&cps::Cps, "`c``s`kr``si`ki", ""), expected);
+}
+
+fn stats_of(interp: &dyn Interpreter, program: &str) -> stats::Stats {
+    let mut buf = Vec::<u8>::new();
+    let mut input = "".chars();
+    let program = parse_str(program).unwrap();
+    let mut stats = stats::Stats::new();
+    {
+        let mut ctx = Ctx::new(&mut buf, &mut input);
+        ctx.set_tracer(Some(&mut stats));
+        let _ = interp.eval(program, &mut ctx);
+    }
+    stats
+}
+
+#[test]
+fn statistics() {
+    let s = stats_of(&small_step::SmallStep, "``cir");
+    assert_eq!(s.total_applications, 4);
+    assert_eq!(s.applications_of("c"), 1);
+    assert_eq!(s.applications_of("i"), 1);
+    assert_eq!(s.applications_of(".x"), 1);
+    assert_eq!((s.continuations_captured, s.continuations_invoked), (1, 1));
+    assert_eq!(s.peak_depth, Some(1));
+
+    let s = stats_of(&small_step::SmallStep, "```s`kd``s`kki`.ai");
+    assert_eq!(s.applications_of("d"), 1);
+    assert_eq!(s.promises_forced, 0);
+    assert!(s.allocations() > 0);
+    assert!(s.peak_live_terms > 0);
+
+    let s = stats_of(&small_step::SmallStep, "``d`.ai.b");
+    assert_eq!((s.applications_of("d"), s.promises_forced), (1, 1));
+
+    for program in ["```s`kd``s`kki`.ai", "`d`.ai", "``d`.ai`ei", "``d`.ai.b"] {
+        let expected = stats_of(&small_step::SmallStep, program);
+        for &interp in interpreter::all() {
+            let s = stats_of(interp, program);
+            assert_eq!(s.applications, expected.applications, "{} {}", program, interp.name());
+            assert_eq!(s.total_applications, expected.total_applications);
+            assert_eq!(s.promises_forced, expected.promises_forced);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::io::Write;
+use crate::Term;
+use crate::Term::*;
+use crate::parser::{SpanMap, line_col};
+
+/// Something that happened during evaluation.
+#[derive(Debug)]
+pub enum Event<'t> {
+    /// Evaluated `f` is applied to evaluated `x`.
+    Apply { f: &'t Term, x: &'t Term },
+    /// `d` delayed evaluation of `x`.
+    Promise { x: &'t Term },
+    /// `c` captured the current continuation.
+    Capture,
+    /// A continuation was invoked with `x`.
+    Invoke { x: &'t Term },
+    /// A character was printed by `.x` or `r`.
+    Output(char),
+    /// A character was read by `@` (None on EOF).
+    Input(Option<char>),
+}
+
+#[derive(Debug)]
+pub struct Record<'t> {
+    /// Number of applications performed so far
+    /// (for `Event::Apply` it includes this one).
+    pub step: u64,
+    /// Number of pending continuation entries, if the interpreter
+    /// keeps them explicitly (only small-step does).
+    pub depth: Option<usize>,
+    pub event: Event<'t>,
+}
+
+/// Receives events from interpreters, see `Ctx::set_tracer()`.
+pub trait Tracer {
+    fn record(&mut self, r: &Record);
+
+    /// Called after evaluation, flushes the output.
+    /// Returns the first error writing it, tracers stop at errors.
+    fn finish(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Forwards events to several tracers.
+pub struct Tee<'a>(pub Vec<&'a mut dyn Tracer>);
+
+impl Tracer for Tee<'_> {
+    fn record(&mut self, r: &Record) {
+        for t in self.0.iter_mut() {
+            t.record(r);
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        let mut result = Ok(());
+        for t in self.0.iter_mut() {
+            result = result.and(t.finish());
+        }
+        result
+    }
+}
+
+/// Short name of a combinator, as used in trace filters.
+pub fn combinator(t: &Term) -> &'static str {
+    match *t {
+        K => "k",
+        K1(_) => "k1",
+        S => "s",
+        S1(_) => "s1",
+        S2(_, _) => "s2",
+        I => "i",
+        V => "v",
+        D => "d",
+        Promise(_) => "promise",
+        Print(_) => ".x",
+        Read => "@",
+        CompareRead(_) => "?x",
+        Reprint => "|",
+        E => "e",
+        C => "c",
+        Cont(_) | ReifiedCont(_) => "<cont>",
+        Apply(_, _) => "apply",
+    }
+}
+
+impl Event<'_> {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::Apply { .. } => "apply",
+            Event::Promise { .. } => "promise",
+            Event::Capture => "capture",
+            Event::Invoke { .. } => "invoke",
+            Event::Output(_) => "output",
+            Event::Input(_) => "input",
+        }
+    }
+
+    /// Combinator responsible for the event.
+    pub fn combinator(&self) -> &'static str {
+        match *self {
+            Event::Apply { f, .. } => combinator(f),
+            Event::Promise { .. } => "d",
+            Event::Capture => "c",
+            Event::Invoke { .. } => "<cont>",
+            Event::Output(_) => ".x",
+            Event::Input(_) => "@",
+        }
+    }
+}
+
+/// `t.to_string()` cut to at most `width` characters,
+/// the last one is `…` if it was cut.
+pub fn truncated(t: &Term, width: usize) -> String {
+    struct Limited {
+        s: String,
+        left: usize,
+    }
+    impl std::fmt::Write for Limited {
+        fn write_str(&mut self, s: &str) -> std::fmt::Result {
+            for c in s.chars() {
+                if self.left == 0 {
+                    // stops formatting the rest of the term
+                    return Err(std::fmt::Error);
+                }
+                self.left -= 1;
+                self.s.push(c);
+            }
+            Ok(())
+        }
+    }
+    let mut w = Limited { s: String::new(), left: width };
+    if std::fmt::write(&mut w, format_args!("{}", t)).is_err() && w.s.pop().is_some() {
+        w.s.push('…');
+    }
+    w.s
+}
+
+/// Human-readable trace, one line per event.
+pub struct TextTracer<'s> {
+    out: Output<'s>,
+    width: usize,
+    spans: Option<(&'s SpanMap, &'s str)>,
+}
+
+impl<'s> TextTracer<'s> {
+    /// Terms are truncated to `width` characters.
+    pub fn new(out: Box<dyn Write + 's>, width: usize) -> Self {
+        TextTracer {
+            out: Output::new(out),
+            width,
+            spans: None,
+        }
+    }
+
+    /// Only report events caused by these combinators (see `combinator()`).
+    pub fn with_filter(mut self, combinators: Vec<String>) -> Self {
+        self.out.filter = Some(combinators);
+        self
+    }
+
+    /// Annotate terms coming from the source with their positions.
+    pub fn with_spans(mut self, spans: &'s SpanMap, source: &'s str) -> Self {
+        self.spans = Some((spans, source));
+        self
+    }
+
+    fn term(&self, t: &Term) -> String {
+        let mut s = truncated(t, self.width);
+        if let Some((spans, source)) = self.spans {
+            if let Some(span) = spans.get(t) {
+                let (line, column) = line_col(source, span.start);
+                s.push_str(&format!(" (at {}:{})", line, column));
+            }
+        }
+        s
+    }
+}
+
+// Trace output with the combinator filter, shared by the tracers.
+struct Output<'s> {
+    out: Box<dyn Write + 's>,
+    filter: Option<Vec<String>>,
+    // the first write error, reported by finish(),
+    // nothing is written after it
+    error: Option<std::io::Error>,
+}
+
+impl<'s> Output<'s> {
+    fn new(out: Box<dyn Write + 's>) -> Self {
+        Output { out, filter: None, error: None }
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        self.error.is_none() && match self.filter {
+            Some(ref filter) => filter.iter().any(|c| c == event.combinator()),
+            None => true,
+        }
+    }
+
+    fn write_line(&mut self, line: std::fmt::Arguments) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", line) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.error.is_none() {
+            self.out.flush()?;
+        }
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl Tracer for TextTracer<'_> {
+    fn record(&mut self, r: &Record) {
+        if !self.out.wants(&r.event) {
+            return;
+        }
+        let details = match r.event {
+            Event::Apply { f, x } => format!(" {} {}", self.term(f), self.term(x)),
+            Event::Promise { x } | Event::Invoke { x } => format!(" {}", self.term(x)),
+            Event::Capture => String::new(),
+            Event::Output(c) | Event::Input(Some(c)) => format!(" {:?}", c),
+            Event::Input(None) => String::from(" EOF"),
+        };
+        self.out.write_line(format_args!("{} {}{}", r.step, r.event.name(), details));
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.out.finish()
+    }
+}
+
+/// Version of the `JsonTracer` output format,
+/// bumped on incompatible changes:
+/// - 2: `s` applied to two arguments is written `s2(x, y)`, was `s1(x, y)`.
+pub const JSON_SCHEMA_VERSION: u32 = 2;
+
+/// JSON-lines trace for tooling.
+///
+/// The first line is a header `{"format":"unlambda-trace","version":2}`,
+/// then each event is an object with fields
+/// - `step`: as in `Record`,
+/// - `kind`: `Event::name()`,
+/// - `f`, `x`: terms (for `apply`; `promise` and `invoke` only have `x`),
+/// - `char`: for `output` and `input` (`null` on EOF),
+/// - `depth`: as in `Record` (`null` if unknown).
+pub struct JsonTracer<'s> {
+    out: Output<'s>,
+    width: Option<usize>,
+}
+
+impl<'s> JsonTracer<'s> {
+    /// Writes the header immediately.
+    pub fn new(out: Box<dyn Write + 's>) -> Self {
+        let mut out = Output::new(out);
+        out.write_line(format_args!("{{\"format\":\"unlambda-trace\",\"version\":{}}}", JSON_SCHEMA_VERSION));
+        JsonTracer {
+            out,
+            width: None,
+        }
+    }
+
+    /// Truncate terms to `width` characters (see `truncated()`).
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    /// See `TextTracer::with_filter()`.
+    pub fn with_filter(mut self, combinators: Vec<String>) -> Self {
+        self.out.filter = Some(combinators);
+        self
+    }
+
+    fn term(&self, t: &Term) -> String {
+        json_string(&match self.width {
+            Some(width) => truncated(t, width),
+            None => t.to_string(),
+        })
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+impl Tracer for JsonTracer<'_> {
+    fn record(&mut self, r: &Record) {
+        if !self.out.wants(&r.event) {
+            return;
+        }
+        let details = match r.event {
+            Event::Apply { f, x } => format!(",\"f\":{},\"x\":{}", self.term(f), self.term(x)),
+            Event::Promise { x } | Event::Invoke { x } => format!(",\"x\":{}", self.term(x)),
+            Event::Capture => String::new(),
+            Event::Output(c) | Event::Input(Some(c)) => format!(",\"char\":{}", json_string(&c.to_string())),
+            Event::Input(None) => String::from(",\"char\":null"),
+        };
+        let depth = r.depth.map_or_else(|| String::from("null"), |d| d.to_string());
+        self.out.write_line(format_args!("{{\"step\":{},\"kind\":\"{}\"{},\"depth\":{}}}",
+                            r.step, r.event.name(), details, depth));
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.out.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    #[test]
+    fn truncation() {
+        let t = parse_str("``kv`.xi").unwrap();
+        assert_eq!(truncated(&t, 8), "``kv`.xi");
+        assert_eq!(truncated(&t, 7), "``kv`.…");
+        assert_eq!(truncated(&t, 1), "…");
+        assert_eq!(truncated(&t, 0), "");
+    }
+
+    #[test]
+    fn deep_term() {
+        let mut t = Term::new(I);
+        for _ in 0..1_000_000 {
+            t = Term::new(Promise(t));
+        }
+        let mut out = Vec::new();
+        let mut tracer = JsonTracer::new(Box::new(&mut out));
+        tracer.record(&Record { step: 0, depth: None, event: Event::Promise { x: &t } });
+        tracer.finish().unwrap();
+        drop(tracer);
+        let closing = format!("i{}\",\"depth\":null}}\n", ")".repeat(1_000_000));
+        assert!(String::from_utf8(out).unwrap().ends_with(&closing));
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}é"), "\"a\\\"b\\\\c\\nd\\u0001é\"");
+    }
+}
@@ -0,0 +1,117 @@
+// Exercises the library strictly through its public API.
+
+use std::rc::Rc;
+use unlambda::{interpreter, parse, Ctx, EvalResult, Halt, Interpreter, Term};
+
+fn run(interpreter: &dyn Interpreter, program: &str, input: &str) -> (EvalResult, String) {
+    let program = parse(program).unwrap();
+    let mut output = Vec::new();
+    let mut input = input.chars();
+    let result = {
+        let mut ctx = Ctx::new(&mut output, &mut input);
+        interpreter.eval(program, &mut ctx)
+    };
+    (result, String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn hello_world() {
+    for &interpreter in interpreter::all() {
+        let (result, output) = run(interpreter, "`r```````````.H.e.l.l.o. .w.o.r.l.di", "");
+        assert_eq!(result.unwrap().to_string(), "i");
+        assert_eq!(output, "Hello world\n");
+    }
+}
+
+#[test]
+fn exit_and_input() {
+    for &interpreter in interpreter::all() {
+        let (result, output) = run(interpreter, "``.a`e``@i`|i.b", "x");
+        match result {
+            Err(Halt::Exit(t)) => assert_eq!(t.to_string(), ".x"),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(output, "");
+    }
+}
+
+#[test]
+fn call_cc_capability() {
+    let program = parse("``cir").unwrap();
+    assert!(unlambda::metacircular::contains_c(&program));
+    for &interpreter in interpreter::all() {
+        if !interpreter.supports_call_cc() {
+            assert!(interpreter.check(&program).is_err());
+            continue;
+        }
+        assert!(interpreter.check(&program).is_ok());
+        let (result, output) = run(interpreter, "``cir", "");
+        assert_eq!(result.unwrap().to_string(), "r");
+        assert_eq!(output, "\n");
+    }
+}
+
+#[test]
+fn capabilities() {
+    let names = |query: fn(&dyn Interpreter) -> bool| {
+        interpreter::all().iter().filter(|&&i| query(i)).map(|i| i.name()).collect::<Vec<_>>()
+    };
+    assert_eq!(names(|i| i.supports_stepping()), ["smallstep"]);
+    assert_eq!(names(|i| i.supports_resuming()), ["cps", "smallstep"]);
+}
+
+#[test]
+fn registry() {
+    assert_eq!(interpreter::find("cps").unwrap().name(), "cps");
+    assert_eq!(interpreter::find("SmallStep").unwrap().name(), "smallstep");
+    assert!(interpreter::find("bogus").is_none());
+    assert_eq!(interpreter::default().name(), "smallstep");
+    for &i in interpreter::all() {
+        assert!(std::ptr::eq(interpreter::find(i.name()).unwrap(), i));
+    }
+}
+
+#[test]
+fn parse_errors() {
+    assert!(parse("`k").is_err());
+    let e = parse("`k\n  z").unwrap_err();
+    assert_eq!(e.kind, unlambda::parser::ParseErrorKind::Unrecognized('z'));
+    assert_eq!((e.line, e.column), (2, 3));
+    assert!(matches!(*parse("k").unwrap(), Term::K));
+    let t: Rc<Term> = parse("`kv").unwrap();
+    assert_eq!(t.to_string(), "`kv");
+}
+
+// Set UPDATE_GOLDEN=1 to regenerate the files after intentional changes.
+#[test]
+fn json_trace_golden() {
+    use unlambda::small_step::SmallStep;
+    use unlambda::trace::JsonTracer;
+
+    let cases = [
+        ("promise_and_input", "``d`.ai`@i", "x"),
+        ("call_cc", "``cir", ""),
+        ("escaping_and_exit", "``.\"`.\\i`e``@i`|i", "\t"),
+        ("truncated", "```s``s`k.a`k.b`k.ci", ""),
+    ];
+    for (name, program, input) in cases {
+        let mut trace = Vec::new();
+        {
+            let mut tracer = JsonTracer::new(Box::new(&mut trace));
+            if name == "truncated" {
+                tracer = tracer.with_width(10);
+            }
+            let mut output = Vec::new();
+            let mut input = input.chars();
+            let mut ctx = Ctx::new(&mut output, &mut input);
+            ctx.set_tracer(Some(&mut tracer));
+            let _ = SmallStep.eval(parse(program).unwrap(), &mut ctx);
+        }
+        let trace = String::from_utf8(trace).unwrap();
+        let path = format!("{}/tests/golden/{}.jsonl", env!("CARGO_MANIFEST_DIR"), name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, &trace).unwrap();
+        }
+        assert_eq!(trace, std::fs::read_to_string(&path).unwrap(), "{}", name);
+    }
+}
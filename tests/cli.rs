@@ -0,0 +1,312 @@
+// Runs the command-line tool.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn run(args: &[&str], program: &str) -> Output {
+    run_with_input(args, program.as_bytes(), b"")
+}
+
+fn source_file(program: &[u8]) -> PathBuf {
+    // tests run in parallel
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("unlambda-cli-{}-{}.unl", std::process::id(), n));
+    std::fs::write(&path, program).unwrap();
+    path
+}
+
+fn spawn(args: &[&str], path: Option<&Path>) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_unlambda"))
+        .args(args)
+        .args(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+fn run_with_input(args: &[&str], program: &[u8], input: &[u8]) -> Output {
+    let path = source_file(program);
+    let output = run_args(args, Some(&path), input);
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+fn run_args(args: &[&str], path: Option<&Path>, input: &[u8]) -> Output {
+    let mut child = spawn(args, path);
+    // the program may exit without reading all of it
+    match child.stdin.take().unwrap().write_all(input) {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        r => r.unwrap(),
+    }
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn print_result() {
+    let output = run(&["--print-result"], "`.a``ks.b");
+    assert_eq!(output.stdout, b"a");
+    assert_eq!(output.stderr, b"result: s\n");
+
+    let output = run(&["--print-result"], "``s`kk`d`.ai");
+    assert_eq!(output.stderr, b"result: ``s`kk`d`.ai\n");
+
+    let output = run(&["--print-result"], "``.a`ei.b");
+    assert_eq!(output.stderr, b"exited with i\n");
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run(&["--print-result"], "`ci");
+    assert_eq!(output.stderr, b"result: <cont> (the value contains a continuation, which has no source form)\n");
+}
+
+#[test]
+fn result_status() {
+    let status = |program| run(&["--result-status"], program).status.code();
+    assert_eq!(status("i"), Some(0));
+    assert_eq!(status("`.a`ev"), Some(8));
+    assert_eq!(status("``kvi"), Some(8));
+    assert_eq!(status("k"), Some(6));
+    assert_eq!(status("`k"), Some(2));
+}
+
+#[test]
+fn limits() {
+    let output = run(&["--max-steps=1000"], "```sii``sii");
+    assert_eq!(output.stderr, b"Step limit exceeded\n");
+    assert_eq!(output.status.code(), Some(3));
+
+    // takes more and more memory
+    let output = run(&["--max-memory=100K"], "```sii``s``s`ksk``sii");
+    assert_eq!(output.stderr, b"Memory limit exceeded\n");
+    assert_eq!(output.status.code(), Some(4));
+
+    let output = run(&["--timeout=0.1"], "```sii``sii");
+    assert_eq!(output.stderr, b"Time limit exceeded\n");
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn timeout_values() {
+    for value in ["-1", "nan", "inf", "1e300"] {
+        let output = run(&[&format!("--timeout={}", value)], "i");
+        assert_eq!(output.status.code(), Some(1), "{}", value);
+        assert!(output.stderr.starts_with(format!("error: failed to parse '{}': ", value).as_bytes()));
+    }
+    // representable, but the deadline is too far to be reached
+    assert_eq!(run(&["--timeout=1e19"], "i").status.code(), Some(0));
+}
+
+#[test]
+fn memory_sizes() {
+    assert_eq!(run(&["--max-memory=64K"], "i").status.code(), Some(0));
+    let output = run(&["--max-memory=17179869184G"], "i");
+    assert_eq!(output.stderr, b"error: failed to parse '17179869184G': too large\nSee `unlambda run --help`.\n");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn encodings() {
+    // echoes one character
+    let echo = b"``|`@ii";
+    let output = run_with_input(&["--encoding=bytes"], echo, b"\xff\xfe");
+    assert_eq!(output.stdout, b"\xff");
+    let output = run_with_input(&[], echo, "€".as_bytes());
+    assert_eq!(output.stdout, "€".as_bytes());
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_with_input(&["--encoding=ascii"], echo, b"\xe9");
+    assert_eq!(output.stderr, b"error: non-ASCII byte 0xe9 in input\n");
+    assert_eq!(output.status.code(), Some(7));
+    let output = run_with_input(&[], echo, b"\xe9");
+    assert_eq!(output.status.code(), Some(7));
+
+    // the source is decoded the same way
+    assert_eq!(run_with_input(&["--encoding=bytes"], b"`.\xe9i", b"").stdout, b"\xe9");
+    assert_eq!(run_with_input(&[], "`.éi".as_bytes(), b"").stdout, "é".as_bytes());
+    assert_eq!(run_with_input(&[], b"`.\xe9i", b"").status.code(), Some(2));
+    let output = run_with_input(&["check", "--encoding=ascii"], "`.éi".as_bytes(), b"");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().ends_with(": non-ASCII byte 0xc3 at byte 2\n"));
+}
+
+#[test]
+fn io_errors() {
+    // prints lines forever, like `unlambda prog.unl | head -1`
+    let path = source_file(b"```sri``sri");
+    let mut child = spawn(&[], Some(&path));
+    let mut line = [0; 1];
+    child.stdout.take().unwrap().read_exact(&mut line).unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.stderr, b"");
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run(&[], "i");
+    assert_eq!(output.status.code(), Some(0));
+    let output = Command::new(env!("CARGO_BIN_EXE_unlambda")).arg("/nonexistent.unl").output().unwrap();
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("error: /nonexistent.unl: "));
+    assert_eq!(output.status.code(), Some(7));
+}
+
+#[test]
+fn sources() {
+    let output = run_args(&["-e", "`.ai", "--print-result"], None, b"");
+    assert_eq!((&output.stdout[..], &output.stderr[..]), (&b"a"[..], &b"result: i\n"[..]));
+
+    // ``kiv
+    let output = run_args(&["-e", "`k`.ai", "-e", "`.bi", "-e", "v", "--print-result"], None, b"");
+    assert_eq!((&output.stdout[..], &output.stderr[..]), (&b"ab"[..], &b"result: v\n"[..]));
+
+    let a = source_file(b"`k`.ai # comment");
+    let b = source_file(b"`.b i");
+    let a = a.to_str().unwrap();
+    let b = b.to_str().unwrap();
+    let output = run_args(&["--print-result", a, b, "-"], None, b"v");
+    assert_eq!((&output.stdout[..], &output.stderr[..]), (&b"ab"[..], &b"result: v\n"[..]));
+    let output = run_args(&["--expand", a, b], None, b"");
+    assert_eq!(output.stdout, b"``k`.ai # comment\n`.b i");
+
+    // the program reads its input from a file when stdin holds the program
+    let input = source_file(b"x");
+    let output = run_args(&["--input", input.to_str().unwrap(), "-"], None, b"``|`@ii");
+    assert_eq!(output.stdout, b"x");
+
+    let output = run_args(&[a, "/nonexistent.unl"], None, b"");
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("error: /nonexistent.unl: "));
+    assert_eq!(output.status.code(), Some(7));
+    let output = run_args(&[a, "-e", "i"], None, b"");
+    assert_eq!(output.status.code(), Some(1));
+    let output = run_args(&["-", "-"], None, b"");
+    assert_eq!(output.status.code(), Some(1));
+    let output = run_args(&[], None, b"");
+    assert_eq!(output.status.code(), Some(1));
+
+    // messages name the source
+    let output = run_args(&["-e", "i", "-e", "`k"], None, b"");
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("-e: error: unexpected EOF"));
+    assert_eq!(output.status.code(), Some(2));
+
+    for path in [a, b, input.to_str().unwrap()] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn input_options() {
+    // echoes three characters
+    let echo = "````|`@ii``|`@ii``|`@ii";
+    assert_eq!(run_args(&["--input-string", "ab", "-e", echo], None, b"xyz").stdout, b"ab");
+    assert_eq!(run_args(&["--input-string=ab", "--eof=repeat", "-e", echo], None, b"").stdout, b"aba");
+    assert_eq!(run_args(&["--eof=repeat", "-e", echo], None, b"a").stdout, b"aaa");
+    assert_eq!(run_args(&["--eof=repeat", "-e", echo], None, b"").stdout, b"");
+
+    let input = source_file(b"xy");
+    let args = ["--input", input.to_str().unwrap(), "--eof", "repeat", "-e", echo];
+    assert_eq!(run_args(&args, None, b"").stdout, b"xyx");
+    std::fs::remove_file(&input).unwrap();
+
+    let output = run_args(&["--input=f", "--input-string=ab", "-e", "i"], None, b"");
+    assert_eq!(output.status.code(), Some(1));
+    let output = run_args(&["--eof=soft", "-e", "i"], None, b"");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn subcommands() {
+    let output = run_args(&["--help"], None, b"");
+    let overview = String::from_utf8(output.stdout).unwrap();
+    for command in ["run", "check", "fmt", "trace", "bench", "compile", "minify", "repl"] {
+        assert!(overview.contains(&format!("\n    {:<10}", command)), "{}", overview);
+    }
+    let output = run_args(&["help", "bench"], None, b"");
+    assert!(String::from_utf8(output.stdout).unwrap().contains("--runs <N>"));
+
+    let output = run_args(&["check", "-e", "`.a`ci"], None, b"");
+    assert_eq!(output.stdout, b"applications: 2\nuses: i c .x\ninterpreters: cps, smallstep\n");
+    let output = run_args(&["check", "-e", "`.a"], None, b"");
+    assert_eq!(output.status.code(), Some(2));
+
+    let program = b"let x = `.ai\n`$x $x\n";
+    assert_eq!(run_with_input(&["compile"], program, b"").stdout, b"``.ai `.ai\n");
+    assert_eq!(run_with_input(&["--expand"], program, b"").stdout, b"``.ai `.ai\n");
+
+    let output = run_args(&["bench", "--runs", "1", "-e", "`.ai"], None, b"");
+    assert_eq!(output.stdout.split(|&b| b == b'\n').filter(|l| !l.is_empty()).count(), 3);
+    assert!(output.stderr.is_empty());
+
+    let output = run_args(&["trace", "-e", "``kii"], None, b"");
+    assert_eq!(output.stdout, b"");
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("1 apply k"));
+
+    // options of other commands are rejected
+    let output = run_args(&["check", "--max-steps", "5", "-e", "i"], None, b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("unlambda check --help"));
+
+    // without a command, the arguments are those of run
+    let output = run_with_input(&["--interpreter", "cps"], b"`.xi", b"");
+    assert_eq!(output.stdout, b"x");
+}
+
+#[test]
+fn fmt() {
+    let program = b"``s # first\n`k.a i # last\n";
+    let output = run_with_input(&["fmt"], program, b"");
+    assert_eq!(output.stdout, b"``s  # first\n  `k.a\n  i  # last\n");
+    let output = run_with_input(&["fmt", "--strip-comments"], program, b"");
+    assert_eq!(output.stdout, b"``s`k.ai\n");
+    let output = run_with_input(&["fmt", "--width=6", "--strip-comments"], program, b"");
+    assert_eq!(output.stdout, b"``s\n  `k.a\n  i\n");
+
+    // definitions would be lost
+    let output = run_with_input(&["fmt"], b"let a = `k.a\n``s$a i", b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stdout, b"");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("`let`"));
+    let output = run_with_input(&["fmt", "--lambda"], b"\\x. x", b"");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn minify() {
+    let program = b"# golf\n``s `k.\n\n  . # space\n";
+    let output = run_with_input(&["minify"], program, b"");
+    assert_eq!(output.stdout, b"``s`kr. ");
+    let output = run_args(&["minify", "-e", "`k.a", "-e", "i"], None, b"");
+    assert_eq!(output.stdout, b"``k.ai");
+}
+
+#[test]
+fn trace_errors() {
+    let output = run(&["--trace-file=/dev/full"], "`.ai");
+    assert_eq!(output.stdout, b"a");
+    assert_eq!(output.stderr, b"error: No space left on device (os error 28)\n");
+    assert_eq!(output.status.code(), Some(7));
+
+    // the reader went away, evaluation stops quietly
+    let mut child = spawn(&["trace", "-e", "```sii``sii"], None);
+    drop(child.stderr.take());
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let output = run(&["--trace-file=/dev/full"], "```sii``sii");
+    assert_eq!(output.status.code(), Some(7));
+}
+
+#[test]
+fn stats_errors() {
+    let path = source_file(b"`.ai");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_unlambda"))
+        .args(["--stats"])
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .stderr(std::fs::File::create("/dev/full").unwrap())
+        .spawn()
+        .unwrap();
+    let status = child.wait().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(status.code(), Some(7));
+}